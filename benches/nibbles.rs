@@ -0,0 +1,88 @@
+//! Compare decoding a full memory dump's stitch rows through `Vec`s and through iterators
+//!
+//! Run with `cargo bench --bench nibbles`. Prints the time and the number of
+//! heap allocations for each approach.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use knitty2::{kh940::MEMORY_SIZE, util, Nibble};
+
+/// An odd number of nibbles per row, so every other row starts mid-byte
+const ROW_NIBBLES: usize = 25;
+
+const ITERATIONS: usize = 100;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Count the selected stitches the way the row parser used to, with intermediate `Vec`s
+fn decode_with_vecs(data: &[u8]) -> usize {
+    let nibbles = util::to_nibbles(data);
+
+    nibbles
+        .chunks_exact(ROW_NIBBLES)
+        .map(|row| util::nibble_bits(row).iter().filter(|b| **b).count())
+        .sum()
+}
+
+/// Count the selected stitches the way the row parser does now, without intermediate `Vec`s
+fn decode_with_iters(data: &[u8]) -> usize {
+    let rows = data.len() * 2 / ROW_NIBBLES;
+
+    (0..rows)
+        .map(|row| {
+            let start_index = row * ROW_NIBBLES;
+            let nibbles = util::nibbles_iter(&data[start_index / 2..])
+                .skip(start_index % 2)
+                .take(ROW_NIBBLES);
+            util::nibble_bits_iter(nibbles).filter(|b| *b).count()
+        })
+        .sum()
+}
+
+fn bench(name: &str, data: &[u8], decode: fn(&[u8]) -> usize) -> usize {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    let mut selected = 0;
+    for _ in 0..ITERATIONS {
+        selected = decode(black_box(data));
+    }
+
+    let elapsed = start.elapsed() / ITERATIONS as u32;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / ITERATIONS;
+    println!("{name:<6} {elapsed:>12?} per dump, {allocations:>6} allocations per dump");
+
+    selected
+}
+
+fn main() {
+    let data = (0..MEMORY_SIZE)
+        .map(|i| Nibble::combine_nibbles(Nibble::new((i % 16) as u8), Nibble::new(0xa)))
+        .collect::<Vec<_>>();
+
+    let with_vecs = bench("vecs", &data, decode_with_vecs);
+    let with_iters = bench("iters", &data, decode_with_iters);
+
+    assert_eq!(with_vecs, with_iters);
+}
@@ -0,0 +1,98 @@
+//! Feed arbitrary bytes from the "machine" into the floppy emulator
+//!
+//! Anything the serial line sends may be rejected with an error, but must
+//! never make the emulator panic.
+#![no_main]
+
+use std::{
+    io::{Cursor, Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use knitty2::fdcemu;
+use libfuzzer_sys::fuzz_target;
+use serial::{BaudRate, PortSettings, SerialPort, SerialPortSettings};
+
+/// A serial port that reads from the fuzzer's input and discards everything written
+struct FuzzPort<'a> {
+    input: Cursor<&'a [u8]>,
+}
+
+impl Read for FuzzPort<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for FuzzPort<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for FuzzPort<'_> {
+    fn timeout(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn set_timeout(&mut self, _: Duration) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn configure(&mut self, _: &PortSettings) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn reconfigure(
+        &mut self,
+        _: &dyn Fn(&mut dyn SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_rts(&mut self, _: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, _: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let port = FuzzPort {
+        input: Cursor::new(data),
+    };
+    // The disk is never saved, so the path only has to not exist
+    let mut server = fdcemu::FdcServer::new(
+        Path::new("/nonexistent/knitty2-fuzz.dat"),
+        port,
+        BaudRate::Baud9600,
+        Duration::ZERO,
+    )
+    .unwrap();
+
+    // Every successful step consumes input, so this ends when the input does
+    while server.step().is_ok() {}
+});
@@ -0,0 +1,296 @@
+//! Recording the serial traffic between the machine and the emulator, and playing it back
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Cursor, Read, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serial::{PortSettings, SerialPort, SerialPortSettings};
+
+use crate::error::{KnittyError, Result};
+
+/// A serial port that can record every byte going through it
+///
+/// With a trace file set, each read and write appends a line with a
+/// timestamp in milliseconds, `<` for bytes from the machine or `>` for bytes
+/// to it, and the bytes in hex. Without one, it just forwards to the port.
+pub struct TracedPort<P> {
+    port: P,
+    trace: Option<File>,
+}
+
+impl<P> TracedPort<P> {
+    pub fn new(port: P) -> Self {
+        TracedPort { port, trace: None }
+    }
+
+    /// Write the traffic to `path` from now on, replacing what it held
+    ///
+    /// A trace only replays from the start of a session, so the file is
+    /// truncated rather than appended to.
+    pub fn set_trace_file(&mut self, path: &Path) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        self.trace = Some(file);
+        Ok(())
+    }
+
+    fn record(&mut self, direction: char, bytes: &[u8]) -> std::io::Result<()> {
+        let Some(trace) = &mut self.trace else {
+            return Ok(());
+        };
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let hex = bytes
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        // One write per line, so a crash never leaves half a line behind
+        trace.write_all(format!("{timestamp_ms} {direction} {hex}\n").as_bytes())
+    }
+}
+
+impl<P: Read> Read for TracedPort<P> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.port.read(buf)?;
+        self.record('<', &buf[..count])?;
+        Ok(count)
+    }
+}
+
+impl<P: Write> Write for TracedPort<P> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.port.write(buf)?;
+        self.record('>', &buf[..count])?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<P: SerialPort> SerialPort for TracedPort<P> {
+    fn timeout(&self) -> Duration {
+        self.port.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serial::Result<()> {
+        self.port.set_timeout(timeout)
+    }
+
+    fn configure(&mut self, settings: &PortSettings) -> serial::Result<()> {
+        self.port.configure(settings)
+    }
+
+    fn reconfigure(
+        &mut self,
+        setup: &dyn Fn(&mut dyn SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        self.port.reconfigure(setup)
+    }
+
+    fn set_rts(&mut self, level: bool) -> serial::Result<()> {
+        self.port.set_rts(level)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> serial::Result<()> {
+        self.port.set_dtr(level)
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        self.port.read_cts()
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        self.port.read_dsr()
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        self.port.read_ri()
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        self.port.read_cd()
+    }
+}
+
+/// A serial port that plays back the machine's side of a [`TracedPort`] trace
+///
+/// Reads return the bytes the machine sent, in order, and writes are kept so
+/// they can be compared with what the drive answered when the trace was
+/// recorded. Reading past the end of the trace fails with `UnexpectedEof`.
+pub struct ReplayPort {
+    input: Cursor<Vec<u8>>,
+    expected: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl ReplayPort {
+    /// Replay `input` as the machine's bytes, expecting the drive to answer with `expected`
+    pub fn new(input: Vec<u8>, expected: Vec<u8>) -> Self {
+        ReplayPort {
+            input: Cursor::new(input),
+            expected,
+            output: vec![],
+        }
+    }
+
+    /// Parse a trace file written by [`TracedPort`]
+    pub fn from_trace(trace: &str) -> Result<Self> {
+        let mut input = vec![];
+        let mut expected = vec![];
+
+        for (index, line) in trace.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let invalid = || KnittyError::InvalidTrace {
+                line: index + 1,
+                text: line.to_owned(),
+            };
+            let mut fields = line.split_whitespace();
+            let _timestamp = fields.next();
+            let bytes = match fields.next() {
+                Some("<") => &mut input,
+                Some(">") => &mut expected,
+                _ => return Err(invalid()),
+            };
+            for byte in fields {
+                bytes.push(u8::from_str_radix(byte, 16).map_err(|_| invalid())?);
+            }
+        }
+
+        Ok(ReplayPort::new(input, expected))
+    }
+
+    /// Whether every byte the machine sent has been read
+    pub fn is_exhausted(&self) -> bool {
+        self.input.position() as usize >= self.input.get_ref().len()
+    }
+
+    /// The bytes written to the port so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// The first offset where the written bytes differ from the trace, if any
+    ///
+    /// Bytes written past the end of the trace count as a difference, but
+    /// writing fewer bytes than the trace has doesn't.
+    pub fn diverged_at(&self) -> Option<usize> {
+        self.output
+            .iter()
+            .enumerate()
+            .find(|(offset, byte)| self.expected.get(*offset) != Some(byte))
+            .map(|(offset, _)| offset)
+    }
+
+    /// The bytes the drive answered with in the trace
+    pub fn expected(&self) -> &[u8] {
+        &self.expected
+    }
+}
+
+impl Read for ReplayPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.is_exhausted() && !buf.is_empty() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.input.read(buf)
+    }
+}
+
+impl Write for ReplayPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for ReplayPort {
+    fn timeout(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn set_timeout(&mut self, _: Duration) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn configure(&mut self, _: &PortSettings) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn reconfigure(
+        &mut self,
+        _: &dyn Fn(&mut dyn SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_rts(&mut self, _: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, _: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_traced_port() {
+    let trace_path = std::env::temp_dir().join(format!(
+        "knitty2-test-traced-port-{}.log",
+        std::process::id()
+    ));
+    std::fs::write(&trace_path, "0 < ff\n").unwrap();
+
+    let mut port = TracedPort::new(Cursor::new(b"R2\r".to_vec()));
+    port.set_trace_file(&trace_path).unwrap();
+    let mut command = [0; 3];
+    port.read_exact(&mut command).unwrap();
+    port.write_all(b"00020000").unwrap();
+
+    let trace = std::fs::read_to_string(&trace_path).unwrap();
+    let lines = trace
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1)
+        .collect::<Vec<_>>();
+    assert_eq!(lines, ["< 52 32 0d", "> 30 30 30 32 30 30 30 30"]);
+
+    std::fs::remove_file(&trace_path).unwrap();
+}
@@ -0,0 +1,64 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{Context, Result};
+use serde::Deserialize;
+
+/// Config file picked up from the working directory when `--config` isn't given
+const DEFAULT_CONFIG_PATH: &str = "knitty2.toml";
+
+/// Defaults for command line options, read from a TOML file
+///
+/// Every setting can also be given as a command line flag or an environment
+/// variable (which `.env` files can set). Flags win over environment
+/// variables, which win over the config file, which wins over the built-in
+/// defaults.
+#[derive(Default, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Grayscale cutoff for importing images
+    pub threshold: Option<u8>,
+
+    /// Serial port to emulate the floppy drive on
+    pub port: Option<PathBuf>,
+
+    /// Serial baud rate
+    pub baud: Option<usize>,
+
+    /// Serial read timeout in seconds
+    pub timeout: Option<u64>,
+}
+
+impl Config {
+    /// Read the config at `path`, or `knitty2.toml` if it exists and no path is given
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(Config::default()),
+        };
+
+        let contents = std::fs::read_to_string(path)
+            .context(format!("Could not read config file at {path:?}"))?;
+
+        toml::from_str(&contents).context(format!("Could not parse config file at {path:?}"))
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let config: Config = toml::from_str(
+        r#"
+        threshold = 100
+        port = "/dev/ttyUSB0"
+        baud = 19200
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.threshold, Some(100));
+    assert_eq!(config.port, Some(PathBuf::from("/dev/ttyUSB0")));
+    assert_eq!(config.baud, Some(19200));
+    assert_eq!(config.timeout, None);
+
+    assert!(toml::from_str::<Config>("unknown = 1").is_err());
+}
@@ -0,0 +1,117 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum KnittyError {
+    #[error("Disk image should be {expected} bytes but is {actual}, is this the right file?")]
+    DiskSizeMismatch { expected: usize, actual: usize },
+
+    #[error("Expected {expected} bytes of machine memory, got {actual}")]
+    MemorySizeMismatch { expected: usize, actual: usize },
+
+    #[error("Expected {expected} bytes of sector data, got {actual}")]
+    SectorDataSizeMismatch { expected: usize, actual: usize },
+
+    #[error("Expected {expected} bytes of sector IDs, got {actual}")]
+    SectorIdsSizeMismatch { expected: usize, actual: usize },
+
+    #[error(
+        "Serialized machine memory is {actual} bytes instead of {expected}, the {region} \
+        region is {region_actual} bytes instead of {region_expected}; please report this as a bug"
+    )]
+    SerializedSizeMismatch {
+        expected: usize,
+        actual: usize,
+        region: &'static str,
+        region_expected: usize,
+        region_actual: usize,
+    },
+
+    #[error("Pattern header {index} points outside of the pattern memory")]
+    PatternOutOfBounds { index: usize },
+
+    #[error("Too many patterns, got {count} but the machine holds at most {max}")]
+    TooManyPatterns { count: usize, max: usize },
+
+    #[error(
+        "Patterns require {required} bytes but only {available} bytes of pattern memory are \
+        available, {over} bytes over; remove a pattern or make one smaller",
+        over = .required - .available
+    )]
+    PatternMemoryFull { required: usize, available: usize },
+
+    #[error("Invalid control data: {0}")]
+    InvalidControlData(String),
+
+    #[error("Image is too large ({width}x{height})")]
+    ImageTooLarge { width: u32, height: u32 },
+
+    #[error("Pattern is {width} stitches wide, wider than the {canvas} stitch canvas")]
+    WiderThanCanvas { width: u16, canvas: u16 },
+
+    #[error("Pattern {dimension} {size} is over the machine's limit of {max}")]
+    PatternTooLarge {
+        dimension: &'static str,
+        size: u32,
+        max: u16,
+    },
+
+    #[error("Expected a {expected} byte memo, got {actual} bytes")]
+    MemoSizeMismatch { expected: usize, actual: usize },
+
+    #[error("No pattern numbered {0}")]
+    PatternNotFound(u16),
+
+    #[error("Pattern number {0} is already taken")]
+    PatternNumberTaken(u16),
+
+    #[error("Bitmap rows have different widths")]
+    RaggedBitmap,
+
+    #[error("Invalid pattern file: {0}")]
+    InvalidPatternFile(String),
+
+    #[error("{value} doesn't fit in {width} BCD digits")]
+    BcdOverflow { value: u16, width: u16 },
+
+    #[error("Sectors {start}..{end} out of bounds, the disk has {count} sectors")]
+    SectorOutOfBounds {
+        start: usize,
+        end: usize,
+        count: usize,
+    },
+
+    #[error("Invalid trace file line {line}: {text:?}")]
+    InvalidTrace { line: usize, text: String },
+
+    #[error("Unsupported baud rate {0}")]
+    UnsupportedBaudRate(usize),
+
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Serial(#[from] serial::Error),
+}
+
+pub type Result<T, E = KnittyError> = std::result::Result<T, E>;
+
+/// Return early with a [`KnittyError::ProtocolError`] built from a format string
+macro_rules! protocol_bail {
+    ($($arg:tt)*) => {
+        return Err($crate::error::KnittyError::ProtocolError(format!($($arg)*)))
+    };
+}
+
+/// Return early with a [`KnittyError::ProtocolError`] unless the condition holds
+macro_rules! protocol_ensure {
+    ($cond:expr, $($arg:tt)*) => {
+        if !$cond {
+            $crate::error::protocol_bail!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {protocol_bail, protocol_ensure};
@@ -1,20 +1,45 @@
 use std::{
-    convert::Infallible,
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    ops::Range,
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use eyre::{bail, ensure, Result};
-use serial::{PortSettings, SerialPort};
-use tracing::{debug, trace};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serial::{BaudRate, PortSettings, SerialPort};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    error::{protocol_bail, protocol_ensure, KnittyError, Result},
+    util,
+};
 
 const SECTOR_ID_LEN: usize = 12;
 const SECTOR_DATA_LEN: usize = 1024;
 
 const SECTOR_COUNT: usize = 80;
 
+/// Sectors the machine saves its 32 KiB memory dump to
+const MEMORY_SECTOR_COUNT: usize = 32;
+
+/// OP mode request for the drive's status, which the machine polls before switching to FDC mode
+const OP_DRIVE_STATUS: u8 = 0x07;
+const OP_SWITCH_TO_FDC: u8 = 0x08;
+
+/// Response type and status byte telling the machine the drive is ready
+const OP_DRIVE_STATUS_RESPONSE: u8 = 0x12;
+const DRIVE_READY: u8 = 0x00;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub const DEFAULT_BAUD_RATE: usize = 9600;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3600);
+
 #[derive(Clone)]
 struct Sector {
     id: [u8; SECTOR_ID_LEN],
@@ -22,19 +47,121 @@ struct Sector {
 }
 
 pub struct Disk {
-    sectors: Box<[Sector; SECTOR_COUNT]>,
+    sectors: Vec<Sector>,
 }
 
-enum FdcMode {
+/// The protocol mode the emulated drive is in
+///
+/// The drive starts in `Op` mode, where the machine sends `ZZ`-framed requests.
+/// An OP request with command `0x08` switches to `Fdc` mode, where the machine
+/// sends single letter sector commands until it sends `ZZ` again.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FdcMode {
     Op,
     Fdc,
 }
 
-pub struct FdcServer<P: SerialPort> {
+/// Something the machine asked the emulated drive to do, see [`FdcServer::on_event`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FdcEvent {
+    /// The machine switched the drive into a different protocol mode
+    ModeSwitched { from: FdcMode, to: FdcMode },
+    /// The machine read `count` sectors starting at `psn`
+    SectorsRead { psn: u8, count: u8 },
+    /// The machine wrote `count` sectors starting at `psn`
+    SectorsWritten { psn: u8, count: u8 },
+    /// The machine read the ID of sector `psn`
+    IdRead { psn: u8 },
+    /// The machine wrote the ID of sector `psn`
+    IdWritten { psn: u8 },
+    /// The machine searched for a sector ID, finding it at `psn` if it exists
+    IdSearched { psn: Option<u8> },
+    /// The machine formatted the disk, blanking every sector
+    Formatted,
+}
+
+/// Emulates a Tandy PDD-1 floppy drive over a serial port
+///
+/// [`FdcServer::run`] serves the machine until it's stopped through a
+/// [`StopHandle`], saving the disk after every command that changes it. To
+/// drive the emulator from another event loop, call [`FdcServer::step`]
+/// repeatedly instead: each call blocks until one complete command has been
+/// read from the port and answered. Between steps the server is idle, so it's
+/// safe to inspect [`FdcServer::mode`], persist the disk with
+/// [`FdcServer::save`], or stop altogether.
+///
+/// The server owns its disk unless it's created with [`FdcServer::new_shared`],
+/// which lets other threads look at the disk while the server runs.
+pub struct FdcServer<P: SerialPort, D: DiskStorage = Disk> {
     port: P,
     mode: FdcMode,
-    disk: Disk,
+    disk: D,
     disk_path: PathBuf,
+    session_log: Option<File>,
+    on_event: Option<Box<dyn FnMut(FdcEvent) + Send>>,
+    response_delay: Duration,
+    ephemeral: bool,
+    /// Whether the machine has written to the disk since the last [`FdcServer::step`] returned
+    dirty: bool,
+    stop: StopHandle,
+}
+
+/// Stops a running [`FdcServer`] from another thread, like a Ctrl-C handler
+///
+/// The server can't be interrupted while it waits for the machine, so it
+/// only notices a stop request after the next command. Until then, the
+/// handle tells whether the disk has changes that aren't saved yet. Without
+/// them, exiting the process right away loses nothing.
+#[derive(Clone, Default)]
+pub struct StopHandle {
+    requested: Arc<AtomicBool>,
+    unsaved: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    /// Make [`FdcServer::run`] return after saving the current command's changes
+    pub fn request_stop(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stop_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Whether the machine has written to the disk since it was last saved
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.unsaved.load(Ordering::SeqCst)
+    }
+}
+
+/// Where an [`FdcServer`] keeps its disk
+pub trait DiskStorage {
+    fn read<R>(&self, f: impl FnOnce(&Disk) -> R) -> R;
+    fn write<R>(&mut self, f: impl FnOnce(&mut Disk) -> R) -> R;
+}
+
+/// A disk shared between a running [`FdcServer`] and other threads
+pub type SharedDisk = Arc<Mutex<Disk>>;
+
+impl DiskStorage for Disk {
+    fn read<R>(&self, f: impl FnOnce(&Disk) -> R) -> R {
+        f(self)
+    }
+
+    fn write<R>(&mut self, f: impl FnOnce(&mut Disk) -> R) -> R {
+        f(self)
+    }
+}
+
+/// The lock is only held for a single sector access or save at a time
+impl DiskStorage for SharedDisk {
+    fn read<R>(&self, f: impl FnOnce(&Disk) -> R) -> R {
+        f(&self.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn write<R>(&mut self, f: impl FnOnce(&mut Disk) -> R) -> R {
+        f(&mut self.lock().unwrap_or_else(PoisonError::into_inner))
+    }
 }
 
 impl Sector {
@@ -46,13 +173,48 @@ impl Sector {
 
 impl Disk {
     pub fn new() -> Self {
+        Disk::with_sector_count(SECTOR_COUNT)
+    }
+
+    /// A blank disk with a different number of sectors than the PDD-1's 80
+    fn with_sector_count(count: usize) -> Self {
         Disk {
-            sectors: Box::new([Sector::EMPTY; SECTOR_COUNT]),
+            sectors: vec![Sector::EMPTY; count],
+        }
+    }
+
+    /// Give the disk the sector IDs the machine leaves behind when saving to it
+    ///
+    /// The machine looks up its data by searching for exact 12 byte sector
+    /// IDs, so a disk with all-zero IDs looks unformatted. This gives each of
+    /// the 32 sectors holding the memory dump an ID of `0x01` followed by
+    /// eleven zero bytes, and leaves the remaining sectors zeroed.
+    ///
+    /// This layout is an assumption based on the machine saving a single
+    /// file, number 1, and hasn't been checked against a disk captured from
+    /// a real machine yet. The machine writes the IDs itself before saving,
+    /// so to check it, save from the machine through `emulate --trace-file`
+    /// and look at the 12 bytes sent after each `B` or `C` command.
+    pub fn initialize_sector_ids(&mut self) {
+        for (index, sector) in self.sectors.iter_mut().enumerate() {
+            sector.id = [0; SECTOR_ID_LEN];
+            if index < MEMORY_SECTOR_COUNT {
+                sector.id[0] = 0x01;
+            }
         }
     }
 
+    pub fn sector_count(&self) -> usize {
+        self.sectors.len()
+    }
+
+    /// Zero the ID and data of every sector, like formatting the floppy
+    pub fn format(&mut self) {
+        self.sectors.fill(Sector::EMPTY);
+    }
+
     pub fn flatten_data(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(SECTOR_COUNT * SECTOR_DATA_LEN);
+        let mut data = Vec::with_capacity(self.sector_count() * SECTOR_DATA_LEN);
 
         for sector in self.sectors.iter() {
             data.extend(sector.data);
@@ -61,8 +223,32 @@ impl Disk {
         data
     }
 
-    pub fn set_flattened_data(&mut self, mut data: Vec<u8>) -> Result<()> {
-        data.resize(SECTOR_COUNT * SECTOR_DATA_LEN, 0);
+    /// The 32 KiB machine memory dump stored in the first sectors
+    ///
+    /// This is what [`MachineState::from_memory_dump`] parses and
+    /// [`Disk::set_flattened_data_exact`] stores.
+    ///
+    /// [`MachineState::from_memory_dump`]: crate::kh940::MachineState::from_memory_dump
+    pub fn memory(&self) -> Vec<u8> {
+        self.sectors
+            .iter()
+            .take(MEMORY_SECTOR_COUNT)
+            .flat_map(|sector| sector.data)
+            .collect()
+    }
+
+    /// Replace the data of every sector, leaving the sector IDs alone
+    ///
+    /// `data` must fill every sector exactly, as returned by
+    /// [`Disk::flatten_data`].
+    pub fn set_flattened_data(&mut self, data: Vec<u8>) -> Result<()> {
+        let expected = self.sector_count() * SECTOR_DATA_LEN;
+        if data.len() != expected {
+            return Err(KnittyError::SectorDataSizeMismatch {
+                expected,
+                actual: data.len(),
+            });
+        }
 
         for (i, sector) in self.sectors.iter_mut().enumerate() {
             let start_index = i * SECTOR_DATA_LEN;
@@ -73,20 +259,113 @@ impl Disk {
         Ok(())
     }
 
+    /// Store a flattened machine memory image, which must be exactly 32 KiB
+    ///
+    /// The sectors after the machine memory are cleared.
+    pub fn set_flattened_data_exact(&mut self, mut data: Vec<u8>) -> Result<()> {
+        let expected = MEMORY_SECTOR_COUNT * SECTOR_DATA_LEN;
+        if data.len() != expected {
+            return Err(KnittyError::MemorySizeMismatch {
+                expected,
+                actual: data.len(),
+            });
+        }
+
+        data.resize(self.sector_count() * SECTOR_DATA_LEN, 0);
+        self.set_flattened_data(data)
+    }
+
+    /// The IDs of all sectors back to back, 12 bytes each
+    pub fn flatten_ids(&self) -> Vec<u8> {
+        self.sectors.iter().flat_map(|sector| sector.id).collect()
+    }
+
+    /// Replace the ID of every sector, leaving the sector data alone
+    ///
+    /// `ids` must hold exactly one 12 byte ID per sector, as written by
+    /// [`Disk::flatten_ids`].
+    pub fn set_flattened_ids(&mut self, ids: &[u8]) -> Result<()> {
+        let expected = self.sector_count() * SECTOR_ID_LEN;
+        if ids.len() != expected {
+            return Err(KnittyError::SectorIdsSizeMismatch {
+                expected,
+                actual: ids.len(),
+            });
+        }
+
+        for (sector, id) in self.sectors.iter_mut().zip(ids.chunks_exact(SECTOR_ID_LEN)) {
+            sector.id.copy_from_slice(id);
+        }
+
+        Ok(())
+    }
+
+    /// Read a disk image, decompressing it first if it's gzipped
+    ///
+    /// The image must hold exactly every sector's ID and data, anything
+    /// shorter or longer is probably not a disk image at all.
     pub fn load(&mut self, path: &Path) -> Result<()> {
-        let mut f = BufReader::new(File::open(path)?);
+        self.load_from_reader(File::open(path)?)
+    }
+
+    /// Read a disk image from a stream until it ends, like [`Disk::load`]
+    pub fn load_from_reader(&mut self, mut reader: impl Read) -> Result<()> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
 
-        for sector in self.sectors.iter_mut() {
-            f.read_exact(&mut sector.id)?;
-            f.read_exact(&mut sector.data)?;
+        if data.starts_with(&GZIP_MAGIC) {
+            let mut decompressed = vec![];
+            GzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+            data = decompressed;
+        }
+
+        let expected = self.sector_count() * (SECTOR_ID_LEN + SECTOR_DATA_LEN);
+        if data.len() != expected {
+            return Err(KnittyError::DiskSizeMismatch {
+                expected,
+                actual: data.len(),
+            });
+        }
+
+        for (sector, chunk) in self
+            .sectors
+            .iter_mut()
+            .zip(data.chunks_exact(SECTOR_ID_LEN + SECTOR_DATA_LEN))
+        {
+            let (id, data) = chunk.split_at(SECTOR_ID_LEN);
+            sector.id.copy_from_slice(id);
+            sector.data.copy_from_slice(data);
         }
 
         Ok(())
     }
 
+    /// Write the disk image, gzipping it if the path ends in `.gz`
     pub fn save(&self, path: &Path) -> Result<()> {
         let mut f = BufWriter::new(File::create(path)?);
 
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            let mut gz = GzEncoder::new(&mut f, Compression::default());
+            self.write_sectors(&mut gz)?;
+            gz.finish()?;
+        } else {
+            self.write_sectors(&mut f)?;
+        }
+
+        f.flush()?;
+
+        Ok(())
+    }
+
+    /// Write the disk image uncompressed to a stream, in the format [`Disk::load_from_reader`] reads
+    pub fn save_to(&self, mut writer: impl Write) -> Result<()> {
+        self.write_sectors(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn write_sectors(&self, f: &mut dyn Write) -> Result<()> {
         for sector in self.sectors.iter() {
             f.write_all(&sector.id)?;
             f.write_all(&sector.data)?;
@@ -96,22 +375,60 @@ impl Disk {
     }
 }
 
+impl Default for Disk {
+    fn default() -> Self {
+        Disk::new()
+    }
+}
+
 impl<P: SerialPort> FdcServer<P> {
-    pub fn new(disk_path: &Path, mut port: P) -> Result<Self> {
+    pub fn new(disk_path: &Path, port: P, baud_rate: BaudRate, timeout: Duration) -> Result<Self> {
+        FdcServer::with_storage(disk_path, port, baud_rate, timeout, Disk::new())
+    }
+}
+
+impl<P: SerialPort> FdcServer<P, SharedDisk> {
+    /// Like [`FdcServer::new`], but with the disk behind a lock other threads can share
+    ///
+    /// Get the shared disk with [`FdcServer::disk_handle`]. The server only
+    /// locks it while touching a sector or saving, so readers see the disk as
+    /// it is between commands.
+    pub fn new_shared(
+        disk_path: &Path,
+        port: P,
+        baud_rate: BaudRate,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let disk = Arc::new(Mutex::new(Disk::new()));
+        FdcServer::with_storage(disk_path, port, baud_rate, timeout, disk)
+    }
+
+    /// The disk the server reads and writes, to inspect from another thread
+    pub fn disk_handle(&self) -> SharedDisk {
+        self.disk.clone()
+    }
+}
+
+impl<P: SerialPort, D: DiskStorage> FdcServer<P, D> {
+    fn with_storage(
+        disk_path: &Path,
+        mut port: P,
+        baud_rate: BaudRate,
+        timeout: Duration,
+        mut disk: D,
+    ) -> Result<Self> {
         port.configure(&PortSettings {
-            baud_rate: serial::BaudRate::Baud9600,
+            baud_rate,
             char_size: serial::CharSize::Bits8,
             parity: serial::Parity::ParityNone,
             stop_bits: serial::StopBits::Stop1,
             flow_control: serial::FlowControl::FlowNone,
         })?;
         port.set_rts(true)?;
-        port.set_timeout(Duration::from_secs(3600))?;
-
-        let mut disk = Disk::new();
+        port.set_timeout(timeout)?;
 
         if disk_path.exists() {
-            disk.load(disk_path)?;
+            disk.write(|disk| disk.load(disk_path))?;
         }
 
         Ok(FdcServer {
@@ -119,31 +436,174 @@ impl<P: SerialPort> FdcServer<P> {
             mode: FdcMode::Op,
             disk,
             disk_path: disk_path.to_owned(),
+            session_log: None,
+            on_event: None,
+            response_delay: Duration::ZERO,
+            ephemeral: false,
+            dirty: false,
+            stop: StopHandle::default(),
         })
     }
 
-    pub fn run(&mut self) -> Result<Infallible> {
-        loop {
+    /// Append a JSON line to `path` for every command that modifies the disk
+    pub fn set_session_log(&mut self, path: &Path) -> Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.session_log = Some(file);
+        Ok(())
+    }
+
+    fn log_session_event(&mut self, command: &str, psn: u8, data: &[u8]) -> Result<()> {
+        if let Some(log) = &mut self.session_log {
+            let timestamp_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let hash = util::fnv1a(data);
+
+            writeln!(
+                log,
+                concat!(
+                    r#"{{"timestamp_ms":{},"command":"{}","psn":{},"#,
+                    r#""len":{},"fnv1a":"{:016x}"}}"#,
+                ),
+                timestamp_ms,
+                command,
+                psn,
+                data.len(),
+                hash,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Serve the machine until a stop is requested through
+    /// [`FdcServer::stop_handle`]
+    ///
+    /// Unless the server is ephemeral, the disk is saved after every command
+    /// that changes it.
+    pub fn run(&mut self) -> Result<()> {
+        while !self.stop.is_stop_requested() {
+            let previous_mode = self.mode();
+            let changed = self.step()?;
+
+            if self.mode() != previous_mode {
+                debug!(from = ?previous_mode, to = ?self.mode(), "Switched mode");
+            }
+
+            if changed && !self.ephemeral {
+                self.save()?;
+                self.stop.unsaved.store(false, Ordering::SeqCst);
+            }
+        }
+
+        debug!("Stopped");
+
+        Ok(())
+    }
+
+    /// A handle to stop [`FdcServer::run`] from another thread
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Serve the machine until it switches the drive into FDC mode, then return
+    ///
+    /// This only checks that the machine can talk to the emulator; it
+    /// doesn't touch the disk.
+    pub fn run_handshake(&mut self) -> Result<()> {
+        while self.mode == FdcMode::Op {
             self.step()?;
+        }
+
+        debug!("Handshake complete");
+
+        Ok(())
+    }
+
+    pub fn mode(&self) -> FdcMode {
+        self.mode
+    }
+
+    /// The port the server talks to the machine through
+    pub fn port(&self) -> &P {
+        &self.port
+    }
+
+    /// Write the disk back to the path it was loaded from
+    pub fn save(&self) -> Result<()> {
+        self.disk.read(|disk| disk.save(&self.disk_path))
+    }
+
+    /// Call `handler` whenever the machine reads, writes or searches the disk, or switches mode
+    ///
+    /// The handler runs on the thread driving the server, in the middle of a
+    /// command, so it should return quickly.
+    pub fn on_event(&mut self, handler: impl FnMut(FdcEvent) + Send + 'static) {
+        self.on_event = Some(Box::new(handler));
+    }
+
+    /// Wait this long before every status and data response in FDC mode
+    ///
+    /// This covers all replies to the sector and ID commands, but not the OP
+    /// mode handshake, which the machine doesn't wait on.
+    pub fn set_response_delay(&mut self, delay: Duration) {
+        self.response_delay = delay;
+    }
 
-            self.disk.save(&self.disk_path)?;
+    /// Keep the machine's writes in memory only, so [`FdcServer::run`] never saves the disk
+    ///
+    /// The machine can still write and read back sectors, but the disk image
+    /// on file is left untouched and the changes are lost when the server stops.
+    pub fn set_ephemeral(&mut self, ephemeral: bool) {
+        self.ephemeral = ephemeral;
+    }
+
+    /// Send an FDC mode response after the configured delay
+    fn respond(&mut self, bytes: &[u8]) -> Result<()> {
+        if !self.response_delay.is_zero() {
+            std::thread::sleep(self.response_delay);
         }
+
+        self.port.write_all(bytes)?;
+        Ok(())
     }
 
-    fn step(&mut self) -> Result<()> {
+    fn emit(&mut self, event: FdcEvent) {
+        if let Some(handler) = &mut self.on_event {
+            handler(event);
+        }
+    }
+
+    /// Read and handle a single command from the machine
+    ///
+    /// Returns whether the command changed the disk, so the caller knows when
+    /// it needs saving.
+    pub fn step(&mut self) -> Result<bool> {
+        let previous_mode = self.mode;
+
         match self.mode {
             FdcMode::Op => self.step_op(),
             FdcMode::Fdc => self.step_fdc(),
+        }?;
+
+        if self.mode != previous_mode {
+            self.emit(FdcEvent::ModeSwitched {
+                from: previous_mode,
+                to: self.mode,
+            });
         }
+
+        Ok(std::mem::take(&mut self.dirty))
     }
 
     fn step_op(&mut self) -> Result<()> {
         let zz = read_nonzero(&mut self.port, 2)?;
         if zz != [b'Z', b'Z'] {
-            bail!("Expected ZZ ({:x?}), got {zz:x?}", [b'Z', b'Z']);
+            protocol_bail!("Expected ZZ ({:x?}), got {zz:x?}", [b'Z', b'Z']);
         }
 
-        self.handle_op_mode_request()
+        discard_timed_out_command(self.handle_op_mode_request())
     }
 
     #[tracing::instrument(skip(self))]
@@ -154,15 +614,35 @@ impl<P: SerialPort> FdcServer<P> {
         self.port.read_exact(&mut data)?;
         let expected_checksum = read_single(&mut self.port)?;
 
-        println!("OP: cmd={cmd:x}, datalen={datalen}, expected_checksum={expected_checksum:x}, data={data:x?}");
+        debug!(
+            cmd = %format_args!("{cmd:#04x}"),
+            datalen,
+            checksum = %format_args!("{expected_checksum:#04x}"),
+            data = %format_args!("{data:02x?}"),
+            "OP mode request"
+        );
+
+        let mut request = vec![cmd, datalen];
+        request.extend(&data);
+        let checksum = op_checksum(&request);
+        protocol_ensure!(
+            checksum == expected_checksum,
+            "OP mode request checksum should be {checksum:#04x}, got {expected_checksum:#04x}"
+        );
 
         match cmd {
-            0x8 => {
+            OP_DRIVE_STATUS => {
+                let mut response = vec![OP_DRIVE_STATUS_RESPONSE, 1, DRIVE_READY];
+                response.push(op_checksum(&response));
+                self.port.write_all(&response)?;
+                Ok(())
+            }
+            OP_SWITCH_TO_FDC => {
                 self.mode = FdcMode::Fdc;
                 Ok(())
             }
             _ => {
-                bail!("Unknown command in OP mode: {cmd:x}");
+                protocol_bail!("Unknown command in OP mode: {cmd:x}");
             }
         }
     }
@@ -170,7 +650,7 @@ impl<P: SerialPort> FdcServer<P> {
     fn step_fdc(&mut self) -> Result<()> {
         let cmd = read_single(&mut self.port)?;
 
-        match cmd {
+        let result = match cmd {
             b'\r' => Ok(()),
             b'Z' => self.fdc_op_mode_request(),
             b'A' => self.fdc_read_id_section(),
@@ -178,8 +658,11 @@ impl<P: SerialPort> FdcServer<P> {
             b'B' | b'C' => self.fdc_write_id_section(),
             b'W' | b'X' => self.fdc_write_sector(),
             b'R' => self.fdc_read_sector(),
-            _ => bail!("Unknown command in FDC mode: {cmd:x}"),
-        }
+            b'F' | b'G' => self.fdc_format(),
+            _ => protocol_bail!("Unknown command in FDC mode: {cmd:x}"),
+        };
+
+        discard_timed_out_command(result)
     }
 
     #[tracing::instrument(skip(self))]
@@ -189,23 +672,25 @@ impl<P: SerialPort> FdcServer<P> {
             self.mode = FdcMode::Op;
             self.handle_op_mode_request()
         } else {
-            bail!("Got 'Z' in FDC mode but not followed by another 'Z', got: {cmd:x?}")
+            protocol_bail!("Got 'Z' in FDC mode but not followed by another 'Z', got: {cmd:x?}")
         }
     }
 
     #[tracing::instrument(skip(self))]
     fn fdc_read_id_section(&mut self) -> Result<()> {
         let args = self.read_fdc_args()?;
-        let (psn, _) = parse_psn_lsn(&args)?;
+        let (psn, _) = parse_psn_lsn(&args, self.sector_count())?;
 
         let response = format!("00{psn:02X}0000");
-        self.port.write_all(response.as_bytes())?;
+        self.respond(response.as_bytes())?;
 
         let wait_value = read_single(&mut self.port)?;
-        ensure!(wait_value == b'\r', "Expected \\r, got {wait_value:x}");
+        protocol_ensure!(wait_value == b'\r', "Expected \\r, got {wait_value:x}");
 
-        let sector = &self.disk.sectors[psn as usize];
-        self.port.write_all(&sector.id)?;
+        let id = self.disk.read(|disk| disk.sectors[psn as usize].id);
+        self.respond(&id)?;
+
+        self.emit(FdcEvent::IdRead { psn });
 
         Ok(())
     }
@@ -213,30 +698,33 @@ impl<P: SerialPort> FdcServer<P> {
     #[tracing::instrument(skip(self))]
     fn fdc_search_id_section(&mut self) -> Result<()> {
         let args = self.read_fdc_args()?;
-        ensure!(
+        protocol_ensure!(
             args.is_empty(),
             "There should be no args provided to search_id"
         );
 
-        self.port.write_all(b"00000000")?;
+        self.respond(b"00000000")?;
 
         let mut sector_id = [0; SECTOR_ID_LEN];
         self.port.read_exact(&mut sector_id)?;
 
         debug!("Trying to find sector with ID {sector_id:02x?}");
 
-        if let Some(sector_index) = self
-            .disk
-            .sectors
-            .iter()
-            .position(|sector| sector.id == sector_id)
-        {
+        if let Some(sector_index) = self.disk.read(|disk| {
+            disk.sectors
+                .iter()
+                .position(|sector| sector.id == sector_id)
+        }) {
             debug!("  Found at index {sector_index}");
             let buffer = format!("00{sector_index:02X}0000");
-            self.port.write_all(buffer.as_bytes())?;
+            self.respond(buffer.as_bytes())?;
+            self.emit(FdcEvent::IdSearched {
+                psn: Some(sector_index as u8),
+            });
         } else {
             debug!("  Not found");
-            self.port.write_all(b"40000000")?;
+            self.respond(b"40000000")?;
+            self.emit(FdcEvent::IdSearched { psn: None });
         }
 
         Ok(())
@@ -245,19 +733,23 @@ impl<P: SerialPort> FdcServer<P> {
     #[tracing::instrument(skip(self))]
     fn fdc_write_id_section(&mut self) -> Result<()> {
         let args = self.read_fdc_args()?;
-        let (psn, _) = parse_psn_lsn(&args)?;
+        let (psn, _) = parse_psn_lsn(&args, self.sector_count())?;
 
-        self.port.write_all(format!("00{psn:02X}0000").as_bytes())?;
+        self.respond(format!("00{psn:02X}0000").as_bytes())?;
 
         let mut sector_id = [0; SECTOR_ID_LEN];
         self.port.read_exact(&mut sector_id)?;
 
         debug!("Setting sector ID for index {psn} to {sector_id:02x?}");
 
-        let mut sector = &mut self.disk.sectors[psn as usize];
-        sector.id = sector_id;
+        self.mark_dirty();
+        self.disk
+            .write(|disk| disk.sectors[psn as usize].id = sector_id);
+
+        self.log_session_event("write_id_section", psn, &sector_id)?;
 
-        self.port.write_all(format!("00{psn:02X}0000").as_bytes())?;
+        self.respond(format!("00{psn:02X}0000").as_bytes())?;
+        self.emit(FdcEvent::IdWritten { psn });
 
         Ok(())
     }
@@ -265,39 +757,94 @@ impl<P: SerialPort> FdcServer<P> {
     #[tracing::instrument(skip(self))]
     fn fdc_write_sector(&mut self) -> Result<()> {
         let args = self.read_fdc_args()?;
-        let (psn, _) = parse_psn_lsn(&args)?;
+        let (psn, lsn) = parse_psn_lsn(&args, self.sector_count())?;
+        let sectors = sector_range(psn, lsn, self.sector_count())?;
 
-        self.port.write_all(format!("00{psn:02X}0000").as_bytes())?;
+        self.respond(format!("00{psn:02X}0000").as_bytes())?;
 
-        let mut data = [0; SECTOR_DATA_LEN];
+        let mut data = vec![0; sectors.len() * SECTOR_DATA_LEN];
         self.port.read_exact(&mut data)?;
 
         debug!("Data received");
         trace!("  data = {data:02x?}");
 
-        let mut sector = &mut self.disk.sectors[psn as usize];
-        sector.data = data;
+        self.mark_dirty();
+        self.disk.write(|disk| {
+            for (sector, chunk) in disk.sectors[sectors.clone()]
+                .iter_mut()
+                .zip(data.chunks_exact(SECTOR_DATA_LEN))
+            {
+                sector.data.copy_from_slice(chunk);
+            }
+        });
+
+        for (index, chunk) in sectors.zip(data.chunks_exact(SECTOR_DATA_LEN)) {
+            self.log_session_event("write_sector", index as u8, chunk)?;
+        }
+
+        self.respond(format!("00{psn:02X}0000").as_bytes())?;
+        self.emit(FdcEvent::SectorsWritten { psn, count: lsn });
 
-        self.port.write_all(format!("00{psn:02X}0000").as_bytes())?;
         Ok(())
     }
 
     #[tracing::instrument(skip(self))]
     fn fdc_read_sector(&mut self) -> Result<()> {
         let args = self.read_fdc_args()?;
-        let (psn, _) = parse_psn_lsn(&args)?;
+        let (psn, lsn) = parse_psn_lsn(&args, self.sector_count())?;
+        let sectors = sector_range(psn, lsn, self.sector_count())?;
 
-        self.port.write_all(format!("00{psn:02X}0000").as_bytes())?;
+        self.respond(format!("00{psn:02X}0000").as_bytes())?;
 
         let wait_value = read_single(&mut self.port)?;
-        ensure!(wait_value == b'\r', "Expected \\r, got {wait_value:x}");
+        protocol_ensure!(wait_value == b'\r', "Expected \\r, got {wait_value:x}");
+
+        let data = self.disk.read(|disk| {
+            disk.sectors[sectors]
+                .iter()
+                .flat_map(|sector| sector.data)
+                .collect::<Vec<_>>()
+        });
+        self.respond(&data)?;
+
+        self.emit(FdcEvent::SectorsRead { psn, count: lsn });
+
+        Ok(())
+    }
+
+    /// Blank the whole disk
+    ///
+    /// `G` formats without verifying, which makes no difference here. The
+    /// optional argument is the sector length code, and only the drive's
+    /// native 1024 byte sectors are emulated, so it's ignored.
+    #[tracing::instrument(skip(self))]
+    fn fdc_format(&mut self) -> Result<()> {
+        let args = self.read_fdc_args()?;
+        debug!("Formatting with args {args:02x?}");
 
-        let sector = &self.disk.sectors[psn as usize];
-        self.port.write_all(&sector.data)?;
+        self.mark_dirty();
+        self.disk.write(Disk::format);
+
+        self.log_session_event("format", 0, &[])?;
+
+        self.respond(b"00000000")?;
+        self.emit(FdcEvent::Formatted);
 
         Ok(())
     }
 
+    /// Note that the current command changed the disk, before changing it
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+        if !self.ephemeral {
+            self.stop.unsaved.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn sector_count(&self) -> usize {
+        self.disk.read(|disk| disk.sector_count())
+    }
+
     fn read_fdc_args(&mut self) -> Result<Vec<Vec<u8>>> {
         let mut buf = vec![];
 
@@ -324,6 +871,67 @@ impl<P: SerialPort> FdcServer<P> {
     }
 }
 
+/// Map a numeric baud rate onto one of the standard rates the serial port supports
+pub fn baud_rate(speed: usize) -> Result<BaudRate> {
+    match BaudRate::from_speed(speed) {
+        BaudRate::BaudOther(_) => Err(KnittyError::UnsupportedBaudRate(speed)),
+        baud_rate => Ok(baud_rate),
+    }
+}
+
+#[test]
+fn test_load_from_reader() {
+    let mut disk = Disk::new();
+    disk.initialize_sector_ids();
+    let mut data = vec![];
+    disk.save_to(&mut data).unwrap();
+
+    let mut loaded = Disk::new();
+    loaded.load_from_reader(&data[..]).unwrap();
+    assert_eq!(loaded.flatten_ids(), disk.flatten_ids());
+
+    assert!(matches!(
+        Disk::new().load_from_reader(&data[..100]),
+        Err(KnittyError::DiskSizeMismatch { actual: 100, .. })
+    ));
+
+    data.push(0);
+    assert!(matches!(
+        Disk::new().load_from_reader(&data[..]),
+        Err(KnittyError::DiskSizeMismatch {
+            expected: 82880,
+            actual: 82881
+        })
+    ));
+}
+
+#[test]
+fn test_gzip_round_trip() {
+    let path = std::env::temp_dir().join("knitty2-test-gzip-round-trip.dat.gz");
+
+    let mut disk = Disk::new();
+    disk.sectors[5].id = [0x55; SECTOR_ID_LEN];
+    disk.sectors[7].data = [0x77; SECTOR_DATA_LEN];
+    disk.save(&path).unwrap();
+
+    let raw = std::fs::read(&path).unwrap();
+    assert!(raw.starts_with(&GZIP_MAGIC));
+
+    let mut loaded = Disk::new();
+    loaded.load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.sectors[5].id, [0x55; SECTOR_ID_LEN]);
+    assert_eq!(loaded.sectors[7].data, [0x77; SECTOR_DATA_LEN]);
+}
+
+#[test]
+fn test_baud_rate() {
+    assert_eq!(baud_rate(9600).unwrap(), BaudRate::Baud9600);
+    assert_eq!(baud_rate(115200).unwrap(), BaudRate::Baud115200);
+    assert!(baud_rate(12345).is_err());
+}
+
 fn read_nonzero(port: &mut dyn Read, count: usize) -> Result<Vec<u8>> {
     let mut buf = Vec::with_capacity(count);
 
@@ -338,28 +946,592 @@ fn read_nonzero(port: &mut dyn Read, count: usize) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// Turn a timeout in the middle of a command into a discarded command
+///
+/// A timeout while waiting for a new command means the machine went away and
+/// is passed on, but once a command has started the machine is evidently
+/// there, so the partial command is dropped and the server waits for the next.
+fn discard_timed_out_command(result: Result<()>) -> Result<()> {
+    match result {
+        Err(KnittyError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
+            warn!("Timed out in the middle of a command, discarding it");
+            Ok(())
+        }
+        other => other,
+    }
+}
+
+/// Checksum of an OP mode block: the one's complement of the low byte of its sum
+fn op_checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
 fn read_single(port: &mut dyn Read) -> Result<u8> {
     let mut buf = [0];
     port.read_exact(&mut buf)?;
     Ok(buf[0])
 }
 
-fn parse_psn_lsn(args: &[Vec<u8>]) -> Result<(u8, u8)> {
+/// The sectors covered by a transfer of `lsn` consecutive sectors starting at `psn`
+fn sector_range(psn: u8, lsn: u8, sector_count: usize) -> Result<Range<usize>> {
+    let start = usize::from(psn);
+    let end = start + usize::from(lsn);
+
+    protocol_ensure!(lsn > 0, "Sector count must be at least 1");
+    if end > sector_count {
+        return Err(KnittyError::SectorOutOfBounds {
+            start,
+            end,
+            count: sector_count,
+        });
+    }
+
+    Ok(start..end)
+}
+
+/// Parse a decimal FDC argument like `5` or `05`, ignoring surrounding whitespace
+///
+/// [`FdcServer::read_fdc_args`] already drops spaces, but the numbers are
+/// trimmed here too so the parser doesn't depend on that.
+fn parse_number(bytes: &[u8]) -> Result<u8> {
+    match std::str::from_utf8(bytes.trim_ascii())
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        Some(n) => Ok(n),
+        None => protocol_bail!("Invalid numeric argument {bytes:02x?}"),
+    }
+}
+
+fn parse_psn_lsn(args: &[Vec<u8>], sector_count: usize) -> Result<(u8, u8)> {
     let mut psn = 0;
     let mut lsn = 1;
 
-    if let Some(psn_arg_bytes) = args.get(0) {
-        psn = std::str::from_utf8(psn_arg_bytes)?.parse::<u8>()?;
-        ensure!(
-            (psn as usize) < SECTOR_COUNT,
-            "Sector index {psn} out of bounds"
-        );
+    if let Some(psn_arg_bytes) = args.first() {
+        psn = parse_number(psn_arg_bytes)?;
+        if usize::from(psn) >= sector_count {
+            return Err(KnittyError::SectorOutOfBounds {
+                start: psn.into(),
+                end: usize::from(psn) + 1,
+                count: sector_count,
+            });
+        }
     }
     if let Some(lsn_arg_bytes) = args.get(1) {
-        lsn = std::str::from_utf8(lsn_arg_bytes)?.parse::<u8>()?;
+        lsn = parse_number(lsn_arg_bytes)?;
     }
 
     debug!("Parsed PSN={psn}, LSN={lsn}");
 
     Ok((psn, lsn))
 }
+
+#[test]
+fn test_parse_number() {
+    assert_eq!(parse_number(b"05").unwrap(), 5);
+    assert_eq!(parse_number(b" 5").unwrap(), 5);
+    assert_eq!(parse_number(b"79 ").unwrap(), 79);
+
+    match parse_number(b"abc") {
+        Err(KnittyError::ProtocolError(message)) => assert!(message.contains("[61, 62, 63]")),
+        other => panic!("Expected a protocol error, got {other:?}"),
+    }
+    assert!(parse_number(b"").is_err());
+    assert!(parse_number(b"256").is_err());
+}
+
+#[test]
+fn test_fdc_args_ignore_spaces() {
+    let mut server = mock_fdc_server(b"R 01, 2\r\r");
+    server.disk.sectors[1].data = [0x11; SECTOR_DATA_LEN];
+
+    server.step().unwrap();
+    assert_eq!(&server.port.output[..8], b"00010000");
+    assert_eq!(server.port.output.len(), 8 + 2 * SECTOR_DATA_LEN);
+}
+
+#[cfg(test)]
+struct MockPort {
+    input: std::io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+    /// Input position at which a single read fails with a timeout
+    timeout_at: Option<u64>,
+}
+
+#[cfg(test)]
+impl MockPort {
+    fn new(input: &[u8]) -> Self {
+        MockPort {
+            input: std::io::Cursor::new(input.to_vec()),
+            output: vec![],
+            timeout_at: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Read for MockPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.timeout_at == Some(self.input.position()) {
+            self.timeout_at = None;
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for MockPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl SerialPort for MockPort {
+    fn timeout(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn set_timeout(&mut self, _: Duration) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn configure(&mut self, _: &PortSettings) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn reconfigure(
+        &mut self,
+        _: &dyn Fn(&mut dyn serial::SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_rts(&mut self, _: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, _: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+fn mock_fdc_server(input: &[u8]) -> FdcServer<MockPort> {
+    let disk_path = std::env::temp_dir().join("knitty2-mock-disk-that-does-not-exist.dat");
+    let mut server = FdcServer::new(
+        &disk_path,
+        MockPort::new(input),
+        BaudRate::Baud9600,
+        DEFAULT_TIMEOUT,
+    )
+    .unwrap();
+    server.mode = FdcMode::Fdc;
+    server
+}
+
+#[test]
+fn test_read_multiple_sectors() {
+    let mut server = mock_fdc_server(b"R2,2\r\r");
+    server.disk.sectors[2].data = [0x22; SECTOR_DATA_LEN];
+    server.disk.sectors[3].data = [0x33; SECTOR_DATA_LEN];
+
+    server.step().unwrap();
+
+    let output = &server.port.output;
+    assert_eq!(&output[..8], b"00020000");
+    assert_eq!(output.len(), 8 + 2 * SECTOR_DATA_LEN);
+    assert!(output[8..8 + SECTOR_DATA_LEN].iter().all(|b| *b == 0x22));
+    assert!(output[8 + SECTOR_DATA_LEN..].iter().all(|b| *b == 0x33));
+}
+
+#[test]
+fn test_step_reports_disk_changes() {
+    let mut input = b"R2\r\rW2\r".to_vec();
+    input.extend([0x44; SECTOR_DATA_LEN]);
+    input.extend(b"\r");
+    let mut server = mock_fdc_server(&input);
+
+    assert!(!server.step().unwrap());
+    assert!(server.step().unwrap());
+    assert!(!server.step().unwrap());
+    assert_eq!(server.disk.sectors[2].data, [0x44; SECTOR_DATA_LEN]);
+}
+
+#[test]
+fn test_replay_trace() {
+    use crate::capture::ReplayPort;
+
+    let trace = "1 < 52 32 0d\n2 > 30 30 30 32 30 30 30 30\n3 < 0d\n";
+    let mut expected = trace.to_owned();
+    expected.push_str(&format!("4 >{}\n", " 22".repeat(SECTOR_DATA_LEN)));
+
+    let replay = |trace: &str, data: u8| {
+        let port = ReplayPort::from_trace(trace).unwrap();
+        let mut server = FdcServer::new(
+            &std::env::temp_dir().join("knitty2-mock-disk-that-does-not-exist.dat"),
+            port,
+            BaudRate::Baud9600,
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+        server.mode = FdcMode::Fdc;
+        server.disk.sectors[2].data = [data; SECTOR_DATA_LEN];
+
+        server.step().unwrap();
+        assert!(server.port().is_exhausted());
+        assert!(server.step().is_err());
+        server.port().diverged_at()
+    };
+
+    assert_eq!(replay(&expected, 0x22), None);
+    assert_eq!(replay(&expected, 0x33), Some(8));
+    assert_eq!(replay(trace, 0x22), Some(8));
+
+    assert!(matches!(
+        ReplayPort::from_trace("1 < 52\n2 ? 30\n"),
+        Err(KnittyError::InvalidTrace { line: 2, .. })
+    ));
+}
+
+#[test]
+fn test_read_sectors_out_of_bounds() {
+    let mut server = mock_fdc_server(b"R79,2\r\r");
+
+    assert!(server.step().is_err());
+}
+
+#[test]
+fn test_sector_bounds_follow_disk_size() {
+    let mut server = mock_fdc_server(b"R99\r\r");
+    server.disk = Disk::with_sector_count(100);
+    server.disk.sectors[99].data = [0x99; SECTOR_DATA_LEN];
+
+    server.step().unwrap();
+    assert!(server.port.output[8..].iter().all(|b| *b == 0x99));
+
+    let mut server = mock_fdc_server(b"R100\r\r");
+    server.disk = Disk::with_sector_count(100);
+
+    match server.step() {
+        Err(KnittyError::SectorOutOfBounds { count, .. }) => assert_eq!(count, 100),
+        other => panic!("Expected an out of bounds error, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_initialize_sector_ids() {
+    let mut disk = Disk::new();
+    disk.initialize_sector_ids();
+
+    let mut expected_id = [0; SECTOR_ID_LEN];
+    expected_id[0] = 0x01;
+    assert!(disk.sectors[..MEMORY_SECTOR_COUNT]
+        .iter()
+        .all(|s| s.id == expected_id));
+    assert!(disk.sectors[MEMORY_SECTOR_COUNT..]
+        .iter()
+        .all(|s| s.id == [0; SECTOR_ID_LEN]));
+}
+
+#[test]
+fn test_set_flattened_data_exact() {
+    let mut disk = Disk::new();
+    let size = MEMORY_SECTOR_COUNT * SECTOR_DATA_LEN;
+
+    for len in [size - 1, size + 1] {
+        match disk.set_flattened_data_exact(vec![0x11; len]) {
+            Err(KnittyError::MemorySizeMismatch { expected, actual }) => {
+                assert_eq!((expected, actual), (size, len));
+            }
+            other => panic!("Expected a size mismatch, got {other:?}"),
+        }
+    }
+    assert!(disk.flatten_data().iter().all(|b| *b == 0));
+
+    disk.set_flattened_data_exact(vec![0x11; size]).unwrap();
+    let data = disk.flatten_data();
+    assert!(data[..size].iter().all(|b| *b == 0x11));
+    assert!(data[size..].iter().all(|b| *b == 0));
+}
+
+#[test]
+fn test_memory() {
+    let mut disk = Disk::new();
+    disk.sectors[MEMORY_SECTOR_COUNT - 1].data = [0x11; SECTOR_DATA_LEN];
+    disk.sectors[MEMORY_SECTOR_COUNT].data = [0x22; SECTOR_DATA_LEN];
+
+    let memory = disk.memory();
+    assert_eq!(memory.len(), MEMORY_SECTOR_COUNT * SECTOR_DATA_LEN);
+    assert!(memory[memory.len() - SECTOR_DATA_LEN..]
+        .iter()
+        .all(|b| *b == 0x11));
+}
+
+#[test]
+fn test_set_flattened_data() {
+    let mut disk = Disk::new();
+    let size = SECTOR_COUNT * SECTOR_DATA_LEN;
+
+    for len in [size - 1, size + 1] {
+        match disk.set_flattened_data(vec![0x11; len]) {
+            Err(KnittyError::SectorDataSizeMismatch { expected, actual }) => {
+                assert_eq!((expected, actual), (size, len));
+            }
+            other => panic!("Expected a size mismatch, got {other:?}"),
+        }
+    }
+    assert!(disk.flatten_data().iter().all(|b| *b == 0));
+
+    disk.set_flattened_data(vec![0x11; size]).unwrap();
+    assert_eq!(disk.flatten_data(), vec![0x11; size]);
+}
+
+#[test]
+fn test_flattened_ids_round_trip() {
+    let mut source = Disk::new();
+    source.initialize_sector_ids();
+    source.sectors[79].id = [0x79; SECTOR_ID_LEN];
+    let ids = source.flatten_ids();
+    assert_eq!(ids.len(), SECTOR_COUNT * SECTOR_ID_LEN);
+
+    let mut target = Disk::new();
+    target.sectors[3].data = [0x33; SECTOR_DATA_LEN];
+    target.set_flattened_ids(&ids).unwrap();
+
+    assert_eq!(target.flatten_ids(), ids);
+    assert_eq!(target.sectors[3].data, [0x33; SECTOR_DATA_LEN]);
+    assert!(matches!(
+        target.set_flattened_ids(&ids[1..]),
+        Err(KnittyError::SectorIdsSizeMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_recover_from_timeout_mid_command() {
+    let mut server = mock_fdc_server(b"R2R3\r\r");
+    server.port.timeout_at = Some(2);
+    server.disk.sectors[3].data = [0x33; SECTOR_DATA_LEN];
+
+    server.step().unwrap();
+    assert!(server.port.output.is_empty());
+    assert_eq!(server.mode(), FdcMode::Fdc);
+
+    server.step().unwrap();
+    assert_eq!(&server.port.output[..8], b"00030000");
+    assert!(server.port.output[8..].iter().all(|b| *b == 0x33));
+}
+
+#[test]
+fn test_timeout_between_commands_is_an_error() {
+    let mut server = mock_fdc_server(b"");
+    server.port.timeout_at = Some(0);
+
+    assert!(matches!(server.step(), Err(KnittyError::Io(_))));
+}
+
+#[test]
+fn test_run_handshake() {
+    let mut server = mock_fdc_server(b"\0ZZ\x08\x00\xf7R0\r\r");
+    server.mode = FdcMode::Op;
+
+    server.run_handshake().unwrap();
+
+    assert_eq!(server.mode(), FdcMode::Fdc);
+    assert!(server.port.output.is_empty());
+}
+
+#[test]
+fn test_drive_status_poll() {
+    let mut server = mock_fdc_server(b"ZZ\x07\x00\xf8ZZ\x08\x00\xf7");
+    server.mode = FdcMode::Op;
+
+    server.step().unwrap();
+    assert_eq!(server.mode(), FdcMode::Op);
+    assert_eq!(server.port.output, [0x12, 0x01, 0x00, 0xec]);
+
+    server.run_handshake().unwrap();
+    assert_eq!(server.mode(), FdcMode::Fdc);
+    assert_eq!(op_checksum(&[0x08, 0x00]), 0xf7);
+}
+
+#[test]
+fn test_write_sector_round_trip() {
+    let mut input = b"ZZ\x08\x00\xf7W3,2\r".to_vec();
+    input.extend([0x33; SECTOR_DATA_LEN]);
+    input.extend([0x44; SECTOR_DATA_LEN]);
+    input.extend(b"R3,2\r\rZZ\x07\x00\xf8");
+    let mut server = mock_fdc_server(&input);
+    server.mode = FdcMode::Op;
+
+    server.step().unwrap();
+    assert_eq!(server.mode(), FdcMode::Fdc);
+
+    server.step().unwrap();
+    assert_eq!(server.disk.sectors[3].data, [0x33; SECTOR_DATA_LEN]);
+    assert_eq!(server.disk.sectors[4].data, [0x44; SECTOR_DATA_LEN]);
+    assert_eq!(server.port.output, b"0003000000030000");
+
+    server.port.output.clear();
+    server.step().unwrap();
+    assert_eq!(&server.port.output[..8], b"00030000");
+    assert_eq!(
+        server.port.output[8..],
+        server.disk.flatten_data()[3 * SECTOR_DATA_LEN..5 * SECTOR_DATA_LEN]
+    );
+
+    server.port.output.clear();
+    server.step().unwrap();
+    assert_eq!(server.mode(), FdcMode::Op);
+    assert_eq!(server.port.output, [0x12, 0x01, 0x00, 0xec]);
+}
+
+#[test]
+fn test_format() {
+    let mut server = mock_fdc_server(b"F5\rR0\r\r");
+    server.disk.initialize_sector_ids();
+    server.disk.sectors[0].data = [0x11; SECTOR_DATA_LEN];
+
+    assert!(server.step().unwrap());
+    assert_eq!(server.port.output, b"00000000");
+    assert!(server.disk.flatten_ids().iter().all(|b| *b == 0));
+    assert!(server.disk.flatten_data().iter().all(|b| *b == 0));
+
+    server.port.output.clear();
+    server.step().unwrap();
+    assert_eq!(server.port.output.len(), 8 + SECTOR_DATA_LEN);
+}
+
+#[test]
+fn test_op_request_checksum() {
+    let mut server = mock_fdc_server(b"ZZ\x08\x00\xf8");
+    server.mode = FdcMode::Op;
+
+    match server.step() {
+        Err(KnittyError::ProtocolError(message)) => assert!(message.contains("0xf7")),
+        other => panic!("Expected a protocol error, got {other:?}"),
+    }
+    assert_eq!(server.mode(), FdcMode::Op);
+}
+
+#[test]
+fn test_events() {
+    let events = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut server = mock_fdc_server(b"ZZ\x08\x00\xf7R1,2\r\r");
+    server.mode = FdcMode::Op;
+    let sink = events.clone();
+    server.on_event(move |event| sink.lock().unwrap().push(event));
+
+    server.step().unwrap();
+    server.step().unwrap();
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        &[
+            FdcEvent::ModeSwitched {
+                from: FdcMode::Op,
+                to: FdcMode::Fdc,
+            },
+            FdcEvent::SectorsRead { psn: 1, count: 2 },
+        ]
+    );
+}
+
+#[test]
+fn test_ephemeral_never_saves() {
+    let mut input = b"W0\r".to_vec();
+    input.extend([0x42; SECTOR_DATA_LEN]);
+    let mut server = mock_fdc_server(&input);
+    server.disk_path = std::env::temp_dir().join("knitty2-test-ephemeral.dat");
+    server.set_ephemeral(true);
+
+    // The machine going away ends the run with an error
+    assert!(server.run().is_err());
+
+    assert_eq!(server.disk.sectors[0].data, [0x42; SECTOR_DATA_LEN]);
+    assert!(!server.disk_path.exists());
+}
+
+#[test]
+fn test_stop_after_saving() {
+    let mut input = b"W0\r".to_vec();
+    input.extend([0x42; SECTOR_DATA_LEN]);
+    let mut server = mock_fdc_server(&input);
+    server.disk_path = std::env::temp_dir().join("knitty2-test-stop.dat");
+    let _ = std::fs::remove_file(&server.disk_path);
+
+    let stop = server.stop_handle();
+    let handler_stop = stop.clone();
+    server.on_event(move |_| {
+        assert!(handler_stop.has_unsaved_changes());
+        handler_stop.request_stop();
+    });
+
+    server.run().unwrap();
+    assert!(!stop.has_unsaved_changes());
+
+    let mut saved = Disk::new();
+    saved.load(&server.disk_path).unwrap();
+    assert_eq!(saved.sectors[0].data, [0x42; SECTOR_DATA_LEN]);
+    std::fs::remove_file(&server.disk_path).unwrap();
+}
+
+#[test]
+fn test_shared_disk() {
+    let mut input = b"W2\r".to_vec();
+    input.extend([0x22; SECTOR_DATA_LEN]);
+    let disk_path = std::env::temp_dir().join("knitty2-mock-disk-that-does-not-exist.dat");
+    let mut server = FdcServer::new_shared(
+        &disk_path,
+        MockPort::new(&input),
+        BaudRate::Baud9600,
+        DEFAULT_TIMEOUT,
+    )
+    .unwrap();
+    server.mode = FdcMode::Fdc;
+    let handle = server.disk_handle();
+
+    std::thread::spawn(move || server.step().unwrap())
+        .join()
+        .unwrap();
+
+    assert_eq!(
+        handle.lock().unwrap().sectors[2].data,
+        [0x22; SECTOR_DATA_LEN]
+    );
+}
+
+#[test]
+fn test_response_delay() {
+    let mut server = mock_fdc_server(b"R0\r\r");
+    server.set_response_delay(Duration::from_millis(20));
+
+    let start = std::time::Instant::now();
+    server.step().unwrap();
+
+    // One delay before the status, one before the sector data
+    assert!(start.elapsed() >= Duration::from_millis(40));
+    assert_eq!(server.port.output.len(), 8 + SECTOR_DATA_LEN);
+}
@@ -0,0 +1,82 @@
+//! Conversions between patterns and the representations used by other knitting software
+
+use image::GrayImage;
+
+use crate::{
+    error::Result,
+    kh940::{ImageOptions, Pattern},
+};
+
+/// Pixel value AYAB reads as a selected needle, knitted in the contrast yarn
+pub const AYAB_SELECTED: u8 = 0x00;
+
+/// Pixel value AYAB reads as an unselected needle, knitted in the main yarn
+pub const AYAB_UNSELECTED: u8 = 0xff;
+
+/// Convert a pattern into an image for the AYAB desktop software
+///
+/// AYAB has no pattern file format of its own: the AYAB desktop software
+/// opens ordinary image files, takes one pixel per stitch and one row per
+/// knitted row, and in its default single color mode selects the needles of
+/// dark pixels for the contrast yarn. Stitches selected in the pattern
+/// therefore become [`AYAB_SELECTED`] pixels and all others
+/// [`AYAB_UNSELECTED`], with no intermediate grays. The image has the same
+/// orientation as the pattern on the machine, so the leftmost column is the
+/// leftmost needle of the pattern.
+///
+/// These are the same pixels as [`Pattern::to_image`]. The separate export
+/// format exists because the PNG export can scale the image to a gauge, add
+/// a needle ruler or row numbers, or invert it, all of which AYAB would knit
+/// as stitches.
+pub fn to_ayab_image(pattern: &Pattern) -> GrayImage {
+    let width = u32::from(pattern.width());
+    let height = u32::from(pattern.height());
+
+    GrayImage::from_fn(width, height, |x, y| {
+        let selected = pattern.rows()[y as usize][x as usize];
+        [if selected {
+            AYAB_SELECTED
+        } else {
+            AYAB_UNSELECTED
+        }]
+        .into()
+    })
+}
+
+/// Read an image written for AYAB back into a pattern
+pub fn from_ayab_image(
+    pattern_number: u16,
+    image: &GrayImage,
+    options: &ImageOptions,
+) -> Result<Pattern> {
+    Pattern::from_image(pattern_number, image, options)
+}
+
+#[test]
+fn test_ayab_round_trip() {
+    let image = GrayImage::from_raw(3, 2, vec![0, 255, 0, 255, 0, 255]).unwrap();
+    let pattern = Pattern::from_image(901, &image, &ImageOptions::default()).unwrap();
+
+    let ayab = to_ayab_image(&pattern);
+    assert_eq!(
+        ayab.as_raw(),
+        &[
+            AYAB_SELECTED,
+            AYAB_UNSELECTED,
+            AYAB_SELECTED,
+            AYAB_UNSELECTED,
+            AYAB_SELECTED,
+            AYAB_UNSELECTED,
+        ]
+    );
+
+    let parsed = from_ayab_image(901, &ayab, &ImageOptions::default()).unwrap();
+    assert_eq!(parsed.rows(), pattern.rows());
+
+    let options = ImageOptions {
+        invert: true,
+        ..ImageOptions::default()
+    };
+    let inverted = from_ayab_image(901, &ayab, &options).unwrap();
+    assert_eq!(inverted.rows(), [[false, true, false], [true, false, true]]);
+}
@@ -1,16 +1,60 @@
-use std::iter::repeat;
+use std::{fmt, iter::repeat_n, ops::Range};
 
-use eyre::{Context, Result};
 use image::GrayImage;
-use tracing::debug;
+use tracing::{debug, warn};
 
-use crate::{util, Nibble};
+use crate::{
+    error::{KnittyError, Result},
+    util, Nibble,
+};
+
+/// Size of the KH-940's memory, which every dump and serialized memory has
+///
+/// Larger memories like the KH-970's aren't supported: their layout isn't
+/// known, so anything past this is ignored when parsing.
+pub const MEMORY_SIZE: usize = 0x8000;
 
 const PATTERN_COUNT: usize = 98;
 
+/// The last header slot is always used as the list terminator
+pub const MAX_PATTERNS: usize = PATTERN_COUNT - 1;
+
+/// Built-in pattern selected when no custom pattern is loaded
+const DEFAULT_LOADED_PATTERN: u16 = 1;
+
+/// Offset from the top of memory where the first pattern's data starts
+const PATTERN_DATA_START: u16 = 0x120;
+
+/// Offset from the top of memory where the pattern header list starts
+const PATTERN_DATA_END: usize = 0x8000 - SERIALIZED_DATA_PATTERN_LIST_LENGTH;
+
 const CONTROL_DATA_SIZE: usize = 23;
 const SERIALIZED_DATA_PATTERN_LIST_LENGTH: usize = 686;
 
+/// Index in a memory dump where the pattern list ends and the pattern memory begins
+const PATTERN_LIST_END: usize = SERIALIZED_DATA_PATTERN_LIST_LENGTH;
+
+/// A named part of the memory dump, its serialized bytes, and where in memory it belongs
+type MemoryRegion<'a> = (&'static str, &'a [u8], Range<usize>);
+
+/// A custom stitch pattern stored in the machine's memory
+///
+/// The KH-940 stores a single needle selection plane per pattern, for the main
+/// bed. The ribber is not electronically controlled, so there is no second bed
+/// selection anywhere in the memory layout: each pattern is exactly a 7 byte
+/// header, the stitch bits and the memo.
+///
+/// Two patterns are equal when everything including the memo matches, see
+/// [`Pattern::equals_ignoring_memo`] for comparing just the stitches.
+///
+/// With the `serde` feature, patterns serialize with one `X` and `_` string
+/// per row, like [`Pattern::to_ascii`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "PatternText", into = "PatternText")
+)]
 pub struct Pattern {
     number: u16,
     rows: Vec<Vec<bool>>,
@@ -19,7 +63,112 @@ pub struct Pattern {
     memo: Vec<u8>,
 }
 
-#[derive(Default, Debug)]
+/// How a pattern's stitches and memo are laid out in machine memory
+///
+/// This is the authoritative description of the encoding [`MachineState::serialize`]
+/// writes and the machine reads, see [`Pattern::serialized_layout`]:
+///
+/// 1. `initial_padding` zero nibbles, so the stitch data fills whole bytes.
+/// 2. Every row from top to bottom, each as `row_nibbles` nibbles: first
+///    `row_pad_bits` zero bits, then the stitches from the rightmost to the
+///    leftmost needle, with a set bit meaning the needle is selected. Rows
+///    are not byte aligned, a row can start in the low nibble of a byte.
+/// 3. The memo, `memo_len` bytes with one entry per two rows.
+///
+/// Bits are numbered from the most significant bit of each byte.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SerializedLayout {
+    /// Nibbles each row takes up, enough for the width rounded up to a multiple of 4
+    pub row_nibbles: usize,
+    /// Zero bits in front of each row's stitches
+    pub row_pad_bits: usize,
+    /// Zero nibbles before the first row, 1 if the rows add up to an odd number of nibbles
+    pub initial_padding: usize,
+    /// Bytes of stitch data including all padding
+    pub stitch_data_len: usize,
+    /// Bytes of memo following the stitch data
+    pub memo_len: usize,
+}
+
+/// Pattern numbers the machine uses for custom patterns
+pub const PATTERN_NUMBERS: std::ops::RangeInclusive<u16> = 901..=998;
+
+/// The widest pattern the machine's 200 needles can knit
+pub const MAX_WIDTH: u16 = 200;
+
+/// The tallest pattern the three digit height field can describe
+pub const MAX_HEIGHT: u16 = 999;
+
+/// Grayscale value below which a pixel becomes a selected stitch
+pub const DEFAULT_THRESHOLD: u8 = 128;
+
+/// Options controlling how grayscale images are turned into stitches
+#[derive(Copy, Clone, Debug)]
+pub struct ImageOptions {
+    /// Pixels darker than this become selected stitches
+    ///
+    /// A pixel exactly at the threshold is not selected, unless
+    /// `threshold_inclusive` is set.
+    pub threshold: u8,
+
+    /// Also select stitches for pixels exactly at the threshold
+    pub threshold_inclusive: bool,
+
+    /// Round each pixel to the nearest multiple of this value before thresholding
+    ///
+    /// Grayscale conversion can differ slightly between platforms and `image`
+    /// versions, which flips anti-aliased pixels close to the threshold. Snapping
+    /// to a coarse set of levels makes imports deterministic across environments.
+    pub quantize_step: Option<u8>,
+
+    /// Flip each row left to right before storing it
+    ///
+    /// Rows are stored so the image's leftmost column ends up on the leftmost
+    /// needle, as seen from the front of the machine. That is the purl side of
+    /// the fabric, so motifs read mirrored on the knit side. Mirroring on
+    /// import makes the knit side match the image instead.
+    pub mirror: bool,
+
+    /// Floyd-Steinberg dither the image before thresholding, for photographs
+    ///
+    /// Each pixel still becomes a stitch by the threshold, but the error
+    /// from rounding it to black or white is carried over to its neighbours,
+    /// so gray areas come out as a mix of selected and unselected stitches.
+    pub dither: bool,
+
+    /// Select stitches for light pixels instead of dark ones
+    ///
+    /// For charts drawn with knitted stitches in white. Dithering and the
+    /// threshold work as usual, only the outcome is flipped.
+    pub invert: bool,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            threshold: DEFAULT_THRESHOLD,
+            threshold_inclusive: false,
+            quantize_step: None,
+            mirror: false,
+            dither: false,
+            invert: false,
+        }
+    }
+}
+
+/// Best guess at what last wrote a memory dump, see [`MachineState::origin_hint`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// Memory built from scratch by knitty2, with all machine data zeroed
+    Knitty2Created,
+    /// Patterns laid out by knitty2, which zeroes all free memory
+    Knitty2,
+    /// Free memory holds leftover bytes, which knitty2 never leaves behind
+    Machine,
+}
+
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct ControlData {
     next_pattern_ptr1: u16,
     unknown1: u16,
@@ -34,41 +183,83 @@ struct ControlData {
     unknown4_2: u8,
 }
 
+/// The machine's memory: its patterns and the machine data around them
+///
+/// With the `serde` feature, the machine data the parser doesn't understand
+/// serializes as hex strings, so serializing a parsed memory dump, reading
+/// it back and calling [`MachineState::serialize`] gives the same bytes.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MachineState {
     patterns: Vec<Pattern>,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     data0: Vec<u8>,
     control_data: ControlData,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     data1: Vec<u8>,
     loaded_pattern: u16,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     data2: Vec<u8>,
+    next_pattern_number: Option<u16>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    inconsistent_patterns: Vec<u16>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    leftover_bytes: usize,
 }
 
 impl MachineState {
-    pub fn from_memory_dump(data: &[u8]) -> Self {
-        let mut patterns = Vec::new();
-
-        for i in 0..PATTERN_COUNT {
-            if let Some(pattern) = Pattern::from_memory_dump(data, i) {
-                patterns.push(pattern);
-            }
+    /// Parse a memory dump, which must be exactly 32 KiB like [`crate::Disk::memory`]
+    pub fn from_memory_dump(data: &[u8]) -> Result<Self> {
+        if data.len() != MEMORY_SIZE {
+            return Err(KnittyError::MemorySizeMismatch {
+                expected: MEMORY_SIZE,
+                actual: data.len(),
+            });
         }
 
+        let patterns = iter_patterns(data).collect::<Result<Vec<_>>>()?;
+
+        let inconsistent_patterns = find_inconsistent_patterns(data);
+        let leftover_bytes = count_leftover_bytes(data, &patterns);
+
         let data0 = data[0x7ee0..0x7f00].to_vec();
         let control_data = ControlData::from_memory_dump(&data[0x7f00..0x7f17]);
 
         debug!(?control_data, "Control data parsed");
 
         let data1 = data[0x7f17..0x7fea].to_vec();
-        let loaded_pattern = util::from_bcd(&util::to_nibbles(&data[0x7fea..0x7fec])[1..]);
+        let loaded_pattern = loaded_pattern_number(data);
         let data2 = data[0x7fec..0x8000].to_vec();
 
-        MachineState {
+        // Only an overridden number needs remembering, the default follows the patterns
+        let next_pattern_number = stored_next_pattern_number(data)
+            .filter(|number| *number != default_next_pattern_number(&patterns));
+
+        Ok(MachineState {
             patterns,
             data0,
             control_data,
             data1,
             loaded_pattern,
             data2,
+            next_pattern_number,
+            inconsistent_patterns,
+            leftover_bytes,
+        })
+    }
+
+    /// A machine memory with no patterns, as if it was just reset
+    pub fn empty() -> Self {
+        MachineState {
+            patterns: vec![],
+            data0: vec![0; 0x7f00 - 0x7ee0],
+            control_data: ControlData::default(),
+            data1: vec![0; 0x7fea - 0x7f17],
+            loaded_pattern: DEFAULT_LOADED_PATTERN,
+            data2: vec![0; 0x8000 - 0x7fec],
+            next_pattern_number: None,
+            inconsistent_patterns: vec![],
+            leftover_bytes: 0,
         }
     }
 
@@ -76,65 +267,275 @@ impl MachineState {
         &self.patterns
     }
 
+    /// Numbers of the patterns whose size doesn't fit the space their header gives them
+    pub fn inconsistent_patterns(&self) -> &[u16] {
+        &self.inconsistent_patterns
+    }
+
+    /// Guess whether the memory was last written by knitty2 or by a real machine
+    ///
+    /// [`MachineState::serialize`] zeroes the unused pattern list slots and
+    /// the free pattern memory, so any other bytes there point at a machine.
+    /// Machine data that is all zeroes, including the unknown control data
+    /// fields, only comes from [`MachineState::empty`]. This is a heuristic:
+    /// a machine with freshly cleared memory looks like knitty2's output.
+    pub fn origin_hint(&self) -> Origin {
+        if self.leftover_bytes > 0 {
+            return Origin::Machine;
+        }
+
+        let blank_data = [&self.data0, &self.data1, &self.data2]
+            .iter()
+            .all(|data| data.iter().all(|b| *b == 0));
+        if blank_data && self.control_data.unknown_fields_zeroed() {
+            Origin::Knitty2Created
+        } else {
+            Origin::Knitty2
+        }
+    }
+
+    pub fn loaded_pattern(&self) -> u16 {
+        self.loaded_pattern
+    }
+
+    /// Set the pattern the machine has loaded, returning whether it exists
+    ///
+    /// Built-in patterns always exist, custom ones only if they are in
+    /// memory. A missing pattern loads the default built-in pattern instead.
+    pub fn set_loaded_pattern(&mut self, number: u16) -> bool {
+        let exists = !PATTERN_NUMBERS.contains(&number) || self.pattern(number).is_some();
+        self.loaded_pattern = if exists {
+            number
+        } else {
+            DEFAULT_LOADED_PATTERN
+        };
+        exists
+    }
+
+    /// The pattern number the machine suggests for the next new pattern
+    ///
+    /// Unless overridden this is one more than the highest pattern number, or
+    /// 901 if there are no patterns. An override that a pattern has taken
+    /// since is ignored.
+    pub fn next_pattern_number(&self) -> u16 {
+        self.next_pattern_number
+            .filter(|number| self.pattern(*number).is_none())
+            .unwrap_or_else(|| default_next_pattern_number(&self.patterns))
+    }
+
+    /// Override the suggested next pattern number, or go back to the default with `None`
+    ///
+    /// The override is stored in the memory dump, so it survives
+    /// [`MachineState::serialize`] and [`MachineState::from_memory_dump`].
+    pub fn set_next_pattern_number(&mut self, number: Option<u16>) {
+        self.next_pattern_number = number;
+    }
+
+    pub fn pattern(&self, number: u16) -> Option<&Pattern> {
+        self.patterns.iter().find(|p| p.number == number)
+    }
+
+    /// Where [`MachineState::serialize`] puts each pattern, as (number, offset, size in bytes)
+    ///
+    /// Patterns are always packed back to back in number order from the
+    /// start of pattern memory, so there are never gaps between them.
+    /// Offsets count from the top of memory, like the control data pointers.
+    pub fn layout_report(&self) -> Vec<(u16, u16, usize)> {
+        let mut offset = PATTERN_DATA_START;
+
+        self.patterns
+            .iter()
+            .map(|pattern| {
+                let entry = (pattern.number, offset, pattern.memory_size());
+                offset += pattern.memory_size() as u16;
+                entry
+            })
+            .collect()
+    }
+
+    /// Every control data field by name, for reverse engineering the unknown ones
+    ///
+    /// The pointers are as parsed from the memory dump, or as
+    /// [`MachineState::serialize`] last wrote them.
+    pub fn control_data_fields(&self) -> [(&'static str, u32); 11] {
+        let c = &self.control_data;
+        [
+            ("next_pattern_ptr1", c.next_pattern_ptr1.into()),
+            ("unknown1", c.unknown1.into()),
+            ("next_pattern_ptr2", c.next_pattern_ptr2.into()),
+            ("last_pattern_end_ptr", c.last_pattern_end_ptr.into()),
+            ("unknown2", c.unknown2.into()),
+            ("last_pattern_start_ptr", c.last_pattern_start_ptr.into()),
+            ("unknown3", c.unknown3),
+            ("header_end_ptr", c.header_end_ptr.into()),
+            ("unknown_ptr", c.unknown_ptr.into()),
+            ("unknown4_1", c.unknown4_1.into()),
+            ("unknown4_2", c.unknown4_2.into()),
+        ]
+    }
+
+    /// Bytes of pattern memory left after storing the current patterns
+    pub fn free_pattern_memory(&self) -> usize {
+        let used = self.patterns.iter().map(|p| p.memory_size()).sum::<usize>();
+        (PATTERN_DATA_END - usize::from(PATTERN_DATA_START)).saturating_sub(used)
+    }
+
     pub fn add_pattern(&mut self, pattern: Pattern) {
         self.patterns.retain(|p| p.number != pattern.number);
         self.patterns.push(pattern);
         self.patterns.sort_unstable_by_key(|p| p.number);
     }
 
-    pub fn serialize(&mut self) -> Vec<u8> {
-        let pattern_layout = {
-            let mut offset = 0x120;
-            let mut layout = Vec::with_capacity(self.patterns.len());
+    /// Add a pattern like [`MachineState::add_pattern`], unless it doesn't fit in the machine
+    ///
+    /// A pattern with the same number is replaced, so its memory counts as
+    /// free. Returns `TooManyPatterns` or `PatternMemoryFull` without changing
+    /// anything if the pattern doesn't fit.
+    pub fn try_add_pattern(&mut self, pattern: Pattern) -> Result<()> {
+        let replaced = self.pattern(pattern.number);
+        let count = self.patterns.len() + usize::from(replaced.is_none());
+        if count > MAX_PATTERNS {
+            return Err(KnittyError::TooManyPatterns {
+                count,
+                max: MAX_PATTERNS,
+            });
+        }
 
-            for pattern in &self.patterns {
-                let data = pattern.serialize_data();
-                let data_len = data.len() as u16;
-                layout.push((offset, pattern, data));
-                offset += data_len;
-            }
+        let available = PATTERN_DATA_END - usize::from(PATTERN_DATA_START);
+        let used = available - self.free_pattern_memory() - replaced.map_or(0, |p| p.memory_size());
+        let required = used + pattern.memory_size();
+        if required > available {
+            return Err(KnittyError::PatternMemoryFull {
+                required,
+                available,
+            });
+        }
 
-            layout
-        };
+        self.add_pattern(pattern);
+        Ok(())
+    }
+
+    /// Remove the pattern with the given number, returning whether it existed
+    ///
+    /// If the pattern was loaded, the default built-in pattern is loaded instead.
+    pub fn remove_pattern(&mut self, number: u16) -> bool {
+        let count = self.patterns.len();
+        self.patterns.retain(|p| p.number != number);
+        if self.loaded_pattern == number {
+            self.loaded_pattern = DEFAULT_LOADED_PATTERN;
+        }
+        self.patterns.len() != count
+    }
+
+    /// Give the pattern numbered `from` the number `to`, which must be free
+    ///
+    /// The loaded pattern follows the renumbered pattern.
+    pub fn renumber_pattern(&mut self, from: u16, to: u16) -> Result<()> {
+        if from != to && self.pattern(to).is_some() {
+            return Err(KnittyError::PatternNumberTaken(to));
+        }
+
+        let pattern = self
+            .patterns
+            .iter_mut()
+            .find(|p| p.number == from)
+            .ok_or(KnittyError::PatternNotFound(from))?;
+        pattern.number = to;
+        self.patterns.sort_unstable_by_key(|p| p.number);
+
+        if self.loaded_pattern == from {
+            self.loaded_pattern = to;
+        }
+
+        Ok(())
+    }
+
+    /// Remove all patterns, keeping the machine specific data around them intact
+    pub fn clear_patterns(&mut self) {
+        self.patterns.clear();
+        self.loaded_pattern = DEFAULT_LOADED_PATTERN;
+    }
+
+    /// Recompute all control data pointers from the current patterns
+    ///
+    /// [`MachineState::serialize`] does this too, but this repairs the
+    /// pointers of a parsed memory dump on their own. Returns whether any
+    /// pointer changed.
+    pub fn rebuild_control_data(&mut self) -> Result<bool> {
+        let pattern_layout = checked_pattern_layout(&self.patterns)?;
+
+        let mut control_data = self.control_data;
+        control_data.update(&pattern_layout);
+        control_data.validate()?;
+
+        let changed = control_data != self.control_data;
+        self.control_data = control_data;
+        Ok(changed)
+    }
+
+    pub fn serialize(&mut self) -> Result<Vec<u8>> {
+        let pattern_layout = checked_pattern_layout(&self.patterns)?;
 
         self.control_data.update(&pattern_layout);
 
-        let pattern_layout_data = serialize_pattern_layout(&pattern_layout);
+        let pattern_layout_data =
+            serialize_pattern_layout(&pattern_layout, self.next_pattern_number())?;
         let pattern_mem_pad = serialize_pattern_memory_padding(&pattern_layout);
         let pattern_mem = serialize_pattern_memory(&pattern_layout);
         let control_data = self.control_data.serialize();
-        let loaded_pattern = serialize_loaded_pattern(self.loaded_pattern);
+        let loaded_pattern = serialize_loaded_pattern(self.loaded_pattern)?;
+
+        let mut pattern_memory = pattern_mem_pad;
+        pattern_memory.extend(pattern_mem);
+
+        let regions: [MemoryRegion; 7] = [
+            ("pattern list", &pattern_layout_data, 0..PATTERN_LIST_END),
+            ("pattern memory", &pattern_memory, PATTERN_LIST_END..0x7ee0),
+            ("machine data", &self.data0, 0x7ee0..0x7f00),
+            ("control data", &control_data, 0x7f00..0x7f17),
+            ("machine data", &self.data1, 0x7f17..0x7fea),
+            ("loaded pattern", &loaded_pattern, 0x7fea..0x7fec),
+            ("machine data", &self.data2, 0x7fec..MEMORY_SIZE),
+        ];
+        let data = join_memory_regions(&regions);
+        debug_assert_eq!(
+            data.len(),
+            MEMORY_SIZE,
+            "Serialized memory has the wrong size"
+        );
+        check_memory_size(&data, &regions)?;
 
-        let mut data = vec![];
+        self.control_data.validate()?;
 
-        data.extend(pattern_layout_data);
-        data.extend(pattern_mem_pad);
-        data.extend(pattern_mem);
-        data.extend(&self.data0);
-        data.extend(control_data);
-        data.extend(&self.data1);
-        data.extend(loaded_pattern);
-        data.extend(&self.data2);
+        Ok(data)
+    }
+}
 
-        assert_eq!(data.len(), 32768);
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Origin::Knitty2Created => "knitty2, from scratch",
+            Origin::Knitty2 => "knitty2",
+            Origin::Machine => "knitting machine",
+        })
+    }
+}
 
-        data
+impl Default for MachineState {
+    fn default() -> Self {
+        MachineState::empty()
     }
 }
 
 impl Pattern {
-    fn from_memory_dump(data: &[u8], index: usize) -> Option<Self> {
-        let header = &data[index * 7..(index + 1) * 7];
-
-        let end_offset = u16::from_be_bytes([header[0], header[1]]);
-        if end_offset == 0 {
-            return None;
-        }
-
-        let data_nibbles = util::to_nibbles(&header[2..]);
-        let height = util::from_bcd(&data_nibbles[0..3]);
-        let width = util::from_bcd(&data_nibbles[3..6]);
-        let ptn_num = util::from_bcd(&data_nibbles[7..10]);
+    fn from_memory_dump(data: &[u8], header: &PatternHeader) -> Result<Self> {
+        let PatternHeader {
+            index,
+            end_offset,
+            height,
+            width,
+            number: ptn_num,
+        } = *header;
 
         debug!(
             ?index,
@@ -145,59 +546,81 @@ impl Pattern {
             "Found pattern"
         );
 
+        let out_of_bounds = || KnittyError::PatternOutOfBounds { index };
+
         let memo_size = memo_size(height);
-        let memo_end_pos = 0x7fff - end_offset as usize;
-        let memo_start_pos = memo_end_pos - memo_size;
+        let memo_end_pos = 0x7fff_usize
+            .checked_sub(end_offset as usize)
+            .ok_or_else(out_of_bounds)?;
+        let memo_start_pos = memo_end_pos
+            .checked_sub(memo_size)
+            .ok_or_else(out_of_bounds)?;
 
-        let memo = &data[memo_start_pos + 1..memo_end_pos + 1];
+        let memo = data
+            .get(memo_start_pos + 1..memo_end_pos + 1)
+            .ok_or_else(out_of_bounds)?;
 
         debug!("Memo data: {memo:x?}");
 
-        let pattern_size =
-            ((f32::from(width) / 4.0).ceil() * f32::from(height) / 2.0).ceil() as usize;
+        let pattern_size = stitch_data_size(width, height);
         let pattern_end_pos = memo_start_pos;
-        let pattern_start_pos = pattern_end_pos - pattern_size;
+        let pattern_start_pos = pattern_end_pos
+            .checked_sub(pattern_size)
+            .ok_or_else(out_of_bounds)?;
 
-        let pattern = &data[pattern_start_pos + 1..pattern_end_pos + 1];
+        let pattern = data
+            .get(pattern_start_pos + 1..pattern_end_pos + 1)
+            .ok_or_else(out_of_bounds)?;
 
         debug!("Pattern data: {pattern:x?}");
 
         let parsed_pattern = parse_pattern_rows(width, height, pattern);
 
-        for row in &parsed_pattern {
-            for col in row.iter().copied() {
-                if col {
-                    print!("X");
-                } else {
-                    print!("_");
-                }
-            }
-
-            println!();
-        }
-
-        Some(Pattern {
+        let pattern = Pattern {
             number: ptn_num,
             rows: parsed_pattern,
             height,
             width,
             memo: memo.to_vec(),
-        })
+        };
+        debug!("Pattern {ptn_num} parsed:\n{}", pattern.to_ascii());
+
+        Ok(pattern)
     }
 
-    pub fn from_image(pattern_number: u16, image: &GrayImage) -> Result<Self> {
-        let width = u16::try_from(image.width()).context("Image too wide")?;
-        let height = u16::try_from(image.height()).context("Image too wide")?;
+    pub fn from_image(
+        pattern_number: u16,
+        image: &GrayImage,
+        options: &ImageOptions,
+    ) -> Result<Self> {
+        let width = checked_dimension("width", image.width(), MAX_WIDTH)?;
+        let height = checked_dimension("height", image.height(), MAX_HEIGHT)?;
 
         let memo_size = memo_size(height);
         let memo = vec![0; memo_size];
 
         let mut rows = vec![vec![false; width as usize]; height as usize];
 
+        let selected = |value: u8| {
+            value < options.threshold || (options.threshold_inclusive && value == options.threshold)
+        };
+
+        let mut values = image
+            .pixels()
+            .map(|pixel| match options.quantize_step {
+                Some(step) => quantize(pixel[0], step),
+                None => pixel[0],
+            })
+            .collect::<Vec<_>>();
+        if options.dither {
+            dither(&mut values, usize::from(width), selected);
+        }
+
         for y in 0..height {
             for x in 0..width {
-                let color = image.get_pixel(x.into(), y.into())[0] < 128;
-                rows[y as usize][x as usize] = color;
+                let value = values[usize::from(y) * usize::from(width) + usize::from(x)];
+                let column = if options.mirror { width - 1 - x } else { x };
+                rows[y as usize][column as usize] = selected(value) != options.invert;
             }
         }
 
@@ -210,10 +633,155 @@ impl Pattern {
         })
     }
 
+    /// Build a pattern from rows of stitches, top to bottom, with `true` for selected needles
+    ///
+    /// Every row must be as wide as the first one, and the pattern no larger
+    /// than [`MAX_WIDTH`] by [`MAX_HEIGHT`]. The memo starts out blank.
+    pub fn from_bitmap(pattern_number: u16, rows: Vec<Vec<bool>>) -> Result<Self> {
+        let width = rows.first().map_or(0, |row| row.len());
+        if rows.iter().any(|row| row.len() != width) {
+            return Err(KnittyError::RaggedBitmap);
+        }
+
+        let size = |size: usize| u32::try_from(size).unwrap_or(u32::MAX);
+        let width = checked_dimension("width", size(width), MAX_WIDTH)?;
+        let height = checked_dimension("height", size(rows.len()), MAX_HEIGHT)?;
+
+        Ok(Pattern {
+            number: pattern_number,
+            rows,
+            height,
+            width,
+            memo: vec![0; memo_size(height)],
+        })
+    }
+
+    /// Change the pattern's size, keeping its stitches anchored at the bottom left
+    ///
+    /// The machine knits the bottom row first and counts needles from the
+    /// left, so added rows go on top and added columns on the right, where
+    /// they are knitted last. Shrinking crops from the same sides. The memo
+    /// keeps its existing entries and is padded with blanks or truncated to
+    /// fit the new height.
+    pub fn resize_canvas(&mut self, width: u16, height: u16) {
+        for row in &mut self.rows {
+            row.resize(usize::from(width), false);
+        }
+
+        let old_height = usize::from(self.height);
+        let new_height = usize::from(height);
+        if new_height > old_height {
+            let blank = vec![false; usize::from(width)];
+            self.rows
+                .splice(0..0, repeat_n(blank, new_height - old_height));
+        } else {
+            self.rows.drain(0..old_height - new_height);
+        }
+
+        self.memo.resize(memo_size(height), 0);
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Place the stitches on a blank canvas `width` stitches wide
+    ///
+    /// Without `center` the stitches stay at the left like with
+    /// [`Pattern::resize_canvas`]. Centered, an odd number of blank columns
+    /// puts the extra one on the right.
+    pub fn place_on_canvas(&mut self, width: u16, center: bool) -> Result<()> {
+        if self.width > width {
+            return Err(KnittyError::WiderThanCanvas {
+                width: self.width,
+                canvas: width,
+            });
+        }
+
+        if center {
+            let offset = usize::from((width - self.width) / 2);
+            for row in &mut self.rows {
+                row.splice(0..0, repeat_n(false, offset));
+            }
+            self.width += offset as u16;
+        }
+
+        self.resize_canvas(width, self.height);
+        Ok(())
+    }
+
+    /// Whether the patterns have the same number and stitches, whatever their memos
+    pub fn equals_ignoring_memo(&self, other: &Pattern) -> bool {
+        self.number == other.number
+            && self.width == other.width
+            && self.height == other.height
+            && self.rows == other.rows
+    }
+
+    /// Hash of the pattern's dimensions and stitches, ignoring the number and memo
+    pub fn content_checksum(&self) -> u64 {
+        let mut data = [self.width.to_be_bytes(), self.height.to_be_bytes()].concat();
+        data.extend(
+            self.rows
+                .iter()
+                .flatten()
+                .map(|selected| u8::from(*selected)),
+        );
+        util::fnv1a(&data)
+    }
+
+    /// The sizes and padding used to store this pattern, see [`SerializedLayout`]
+    pub fn serialized_layout(&self) -> SerializedLayout {
+        let (row_nibbles, row_pad_bits, initial_padding) =
+            pattern_data_sizes(self.width, self.height);
+
+        SerializedLayout {
+            row_nibbles,
+            row_pad_bits,
+            initial_padding,
+            stitch_data_len: stitch_data_size(self.width, self.height),
+            memo_len: self.memo.len(),
+        }
+    }
+
+    /// Bytes of pattern memory the stitches and memo take up on the machine
+    pub fn memory_size(&self) -> usize {
+        let layout = self.serialized_layout();
+        layout.stitch_data_len + layout.memo_len
+    }
+
+    pub fn memo(&self) -> &[u8] {
+        &self.memo
+    }
+
+    /// Replace the memo, which must have one byte for every two rows
+    pub fn set_memo(&mut self, memo: Vec<u8>) -> Result<()> {
+        let expected = memo_size(self.height);
+        if memo.len() != expected {
+            return Err(KnittyError::MemoSizeMismatch {
+                expected,
+                actual: memo.len(),
+            });
+        }
+
+        self.memo = memo;
+        Ok(())
+    }
+
     pub fn pattern_number(&self) -> u16 {
         self.number
     }
 
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn rows(&self) -> &[Vec<bool>] {
+        &self.rows
+    }
+
     pub fn to_image(&self) -> GrayImage {
         let mut image = GrayImage::new(u32::from(self.width), u32::from(self.height));
 
@@ -227,18 +795,67 @@ impl Pattern {
         image
     }
 
-    fn serialize_header(&self, offset: u16) -> Vec<u8> {
+    /// Draw the pattern as text, with `X` for selected stitches and `_` for the rest
+    pub fn to_ascii(&self) -> String {
+        let mut text = String::with_capacity((usize::from(self.width) + 1) * self.rows.len());
+
+        for row in &self.rows {
+            text.extend(row.iter().map(|col| if *col { 'X' } else { '_' }));
+            text.push('\n');
+        }
+
+        text
+    }
+
+    /// Describe each row's stitches as run lengths next to the nibbles they're stored as
+    ///
+    /// Rows are listed top to bottom like in [`Pattern::to_ascii`], with runs
+    /// written as a count and `X` or `_`, and the nibbles in hex in the order
+    /// they are stored in memory, leftmost needle last.
+    pub fn debug_rows(&self) -> String {
+        let (_, row_pad_bits, _) = pattern_data_sizes(self.width, self.height);
+        let mut text = String::new();
+
+        for (index, row) in self.rows.iter().enumerate() {
+            let mut runs = vec![];
+            for stitch in row.iter().copied() {
+                match runs.last_mut() {
+                    Some((selected, count)) if *selected == stitch => *count += 1,
+                    _ => runs.push((stitch, 1)),
+                }
+            }
+            let runs = runs
+                .iter()
+                .map(|(selected, count)| format!("{count}{}", if *selected { 'X' } else { '_' }))
+                .collect::<Vec<_>>();
+
+            let bits = serialized_row_bits(row, row_pad_bits).collect::<Vec<_>>();
+            let nibbles = bits
+                .chunks_exact(4)
+                .map(|nibble| {
+                    let value = nibble.iter().fold(0, |n, bit| n << 1 | u32::from(*bit));
+                    char::from_digit(value, 16).unwrap_or('?')
+                })
+                .collect::<String>();
+
+            text.push_str(&format!("{index:>3}: {:<40} {nibbles}\n", runs.join(" ")));
+        }
+
+        text
+    }
+
+    fn serialize_header(&self, offset: u16) -> Result<Vec<u8>> {
         let mut data = vec![0, 0];
         data[0..2].copy_from_slice(&offset.to_be_bytes());
 
         let mut header_nibbles = Vec::with_capacity(10);
-        header_nibbles.extend(util::to_bcd(self.height, 3));
-        header_nibbles.extend(util::to_bcd(self.width, 3));
-        header_nibbles.extend(util::to_bcd(self.number, 4));
+        header_nibbles.extend(util::to_bcd_fixed(self.height, 3)?);
+        header_nibbles.extend(util::to_bcd_fixed(self.width, 3)?);
+        header_nibbles.extend(util::to_bcd_fixed(self.number, 4)?);
 
         data.extend(util::from_nibbles(&header_nibbles));
 
-        data
+        Ok(data)
     }
 
     fn serialize_data(&self) -> Vec<u8> {
@@ -247,8 +864,7 @@ impl Pattern {
         let mut bits = vec![false; initial_padding * 4];
 
         for row in &self.rows {
-            bits.extend(repeat(false).take(row_pad_bits));
-            bits.extend(row.iter().copied().rev());
+            bits.extend(serialized_row_bits(row, row_pad_bits));
         }
 
         let mut serialized = util::bits_to_bytes(&bits);
@@ -276,6 +892,15 @@ impl ControlData {
         }
     }
 
+    fn unknown_fields_zeroed(&self) -> bool {
+        self.unknown1 == 0
+            && self.unknown2 == 0
+            && self.unknown3 == 0
+            && self.unknown_ptr == 0
+            && self.unknown4_1 == 0
+            && self.unknown4_2 == 0
+    }
+
     fn update(&mut self, pattern_layout: &[(u16, &Pattern, Vec<u8>)]) {
         let last_pattern_start;
         let last_pattern_end;
@@ -286,7 +911,9 @@ impl ControlData {
             last_pattern_start = *end + data.len() as u16;
             next_pattern_ptr = last_pattern_start + 1;
         } else {
-            next_pattern_ptr = 0x120;
+            // An empty memory points the next pattern at the start of pattern
+            // memory, and leaves every other pointer zeroed
+            next_pattern_ptr = PATTERN_DATA_START;
             last_pattern_start = 0;
             last_pattern_end = 0;
         }
@@ -302,6 +929,59 @@ impl ControlData {
         self.header_end_ptr = (0x8000 - (7 * pattern_layout.len()) - 7) as u16;
     }
 
+    /// Check that the pointers are consistent with each other and point inside
+    /// the pattern memory, since the machine won't accept a disk otherwise
+    fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(KnittyError::InvalidControlData(message));
+
+        if self.last_pattern_end_ptr == 0 {
+            if self.next_pattern_ptr1 != PATTERN_DATA_START
+                || self.next_pattern_ptr2 != 0
+                || self.last_pattern_start_ptr != 0
+            {
+                return invalid(format!(
+                    "Inconsistent pointers for an empty disk: {self:x?}"
+                ));
+            }
+        } else {
+            if self.last_pattern_end_ptr < PATTERN_DATA_START {
+                return invalid(format!(
+                    "Last pattern end {:#x} is below the pattern memory start {PATTERN_DATA_START:#x}",
+                    self.last_pattern_end_ptr
+                ));
+            }
+            if self.last_pattern_start_ptr < self.last_pattern_end_ptr {
+                return invalid(format!(
+                    "Last pattern start {:#x} is below its end {:#x}",
+                    self.last_pattern_start_ptr, self.last_pattern_end_ptr
+                ));
+            }
+            if usize::from(self.last_pattern_start_ptr) > PATTERN_DATA_END {
+                return invalid(format!(
+                    "Last pattern start {:#x} overlaps the pattern list at {PATTERN_DATA_END:#x}",
+                    self.last_pattern_start_ptr
+                ));
+            }
+            if self.next_pattern_ptr1 != self.last_pattern_start_ptr + 1
+                || self.next_pattern_ptr2 != self.next_pattern_ptr1
+            {
+                return invalid(format!(
+                    "Next pattern pointers {:#x}/{:#x} don't follow the last pattern start {:#x}",
+                    self.next_pattern_ptr1, self.next_pattern_ptr2, self.last_pattern_start_ptr
+                ));
+            }
+        }
+
+        if !(PATTERN_DATA_END..=0x8000 - 7).contains(&usize::from(self.header_end_ptr)) {
+            return invalid(format!(
+                "Header end {:#x} is outside of the pattern list",
+                self.header_end_ptr
+            ));
+        }
+
+        Ok(())
+    }
+
     fn serialize(&self) -> [u8; CONTROL_DATA_SIZE] {
         let mut data = [0; CONTROL_DATA_SIZE];
 
@@ -321,14 +1001,285 @@ impl ControlData {
     }
 }
 
+/// [`Pattern`] as serialized with serde, with rows written like [`Pattern::to_ascii`]
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatternText {
+    number: u16,
+    width: u16,
+    height: u16,
+    rows: Vec<String>,
+    memo: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl From<Pattern> for PatternText {
+    fn from(pattern: Pattern) -> Self {
+        PatternText {
+            number: pattern.number,
+            width: pattern.width,
+            height: pattern.height,
+            rows: pattern.to_ascii().lines().map(String::from).collect(),
+            memo: pattern.memo,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<PatternText> for Pattern {
+    type Error = KnittyError;
+
+    fn try_from(text: PatternText) -> Result<Self> {
+        let rows = text
+            .rows
+            .iter()
+            .map(|row| {
+                row.chars()
+                    .map(|stitch| match stitch {
+                        'X' => Ok(true),
+                        '_' => Ok(false),
+                        other => Err(KnittyError::InvalidPatternFile(format!(
+                            "Unknown stitch {other:?}, expected X or _"
+                        ))),
+                    })
+                    .collect()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut pattern = Pattern::from_bitmap(text.number, rows)?;
+        if (pattern.width, pattern.height) != (text.width, text.height) {
+            return Err(KnittyError::InvalidPatternFile(format!(
+                "Pattern is {}x{} but its rows are {}x{}",
+                text.width, text.height, pattern.width, pattern.height
+            )));
+        }
+        pattern.set_memo(text.memo)?;
+
+        Ok(pattern)
+    }
+}
+
+/// Serialize bytes as a hex string, which is a lot shorter than a JSON array
+#[cfg(feature = "serde")]
+mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let text = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+
+        (0..text.len())
+            .step_by(2)
+            .map(|i| {
+                text.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| D::Error::custom(format!("Invalid hex byte at offset {i}")))
+            })
+            .collect()
+    }
+}
+
+fn quantize(value: u8, step: u8) -> u8 {
+    if step <= 1 {
+        return value;
+    }
+
+    let step = u16::from(step);
+    let rounded = (u16::from(value) + step / 2) / step * step;
+    rounded.min(255) as u8
+}
+
+/// Floyd-Steinberg dither a grayscale buffer `width` pixels wide to black and white
+///
+/// Pixels `is_black` accepts become black and the rest white, and the
+/// difference to the original value is spread over the pixels to the right
+/// and below that haven't been visited yet.
+fn dither(values: &mut [u8], width: usize, is_black: impl Fn(u8) -> bool) {
+    let mut levels = values.iter().map(|v| i16::from(*v)).collect::<Vec<_>>();
+
+    for (index, value) in values.iter_mut().enumerate() {
+        let level = levels[index];
+        *value = if is_black(level.clamp(0, 255) as u8) {
+            0
+        } else {
+            255
+        };
+        let error = level - i16::from(*value);
+
+        let x = index % width;
+        let mut spread = |offset: usize, weight: i16| {
+            if let Some(level) = levels.get_mut(index + offset) {
+                *level += error * weight / 16;
+            }
+        };
+        if x + 1 < width {
+            spread(1, 7);
+            spread(width + 1, 1);
+        }
+        if x > 0 {
+            spread(width - 1, 3);
+        }
+        spread(width, 5);
+    }
+}
+
+#[test]
+fn test_quantize() {
+    assert_eq!(quantize(120, 16), 128);
+    assert_eq!(quantize(135, 16), 128);
+    assert_eq!(quantize(119, 16), 112);
+    assert_eq!(quantize(250, 16), 255);
+    assert_eq!(quantize(77, 1), 77);
+}
+
+/// Convert an image dimension to a pattern dimension, if the machine can store it
+fn checked_dimension(dimension: &'static str, size: u32, max: u16) -> Result<u16> {
+    u16::try_from(size)
+        .ok()
+        .filter(|size| *size <= max)
+        .ok_or(KnittyError::PatternTooLarge {
+            dimension,
+            size,
+            max,
+        })
+}
+
 fn memo_size(height: u16) -> usize {
-    (if height % 2 == 0 {
+    (if height.is_multiple_of(2) {
         height / 2
     } else {
         height / 2 + 1
     }) as usize
 }
 
+/// The 7 byte header in the pattern list describing a stored pattern
+#[derive(Copy, Clone, Debug)]
+struct PatternHeader {
+    index: usize,
+    end_offset: u16,
+    height: u16,
+    width: u16,
+    number: u16,
+}
+
+/// Lazily read the headers of the patterns in a memory dump, skipping unused slots
+fn pattern_headers(data: &[u8]) -> impl Iterator<Item = PatternHeader> + '_ {
+    data[..data.len().min(PATTERN_COUNT * 7)]
+        .chunks_exact(7)
+        .enumerate()
+        .filter_map(|(index, header)| {
+            let end_offset = u16::from_be_bytes([header[0], header[1]]);
+            if end_offset == 0 {
+                return None;
+            }
+
+            let nibbles = util::to_nibbles(&header[2..]);
+            Some(PatternHeader {
+                index,
+                end_offset,
+                height: util::from_bcd(&nibbles[0..3]),
+                width: util::from_bcd(&nibbles[3..6]),
+                number: util::from_bcd(&nibbles[7..10]),
+            })
+        })
+}
+
+/// Numbers of the patterns in a memory dump, without parsing their stitches
+pub fn pattern_numbers(data: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    pattern_headers(data).map(|h| h.number)
+}
+
+/// The next pattern number stored after the last pattern header, if it's set
+fn stored_next_pattern_number(data: &[u8]) -> Option<u16> {
+    let terminator = data[..PATTERN_COUNT * 7]
+        .chunks_exact(7)
+        .find(|header| header[0..2] == [0, 0])?;
+
+    Some(util::from_bcd(&util::to_nibbles(&terminator[5..7]))).filter(|number| *number != 0)
+}
+
+/// One more than the highest pattern number, or 901 without patterns
+fn default_next_pattern_number(patterns: &[Pattern]) -> u16 {
+    patterns.iter().map(|p| p.number).max().unwrap_or(900) + 1
+}
+
+/// Number of the pattern the machine had loaded when the memory was dumped
+pub fn loaded_pattern_number(data: &[u8]) -> u16 {
+    util::from_bcd(&util::to_nibbles(&data[0x7fea..0x7fec])[1..])
+}
+
+/// Parse the patterns in a memory dump one at a time
+///
+/// Unlike [`MachineState::from_memory_dump`] this only keeps one pattern in
+/// memory at a time, for processing many disks in a row.
+pub fn iter_patterns(data: &[u8]) -> impl Iterator<Item = Result<Pattern>> + '_ {
+    pattern_headers(data).map(|header| Pattern::from_memory_dump(data, &header))
+}
+
+/// Count the non-zero bytes in the parts of a memory dump no pattern uses
+///
+/// These are the pattern list slots after the terminator, and the gap between
+/// the pattern list and the pattern data growing down from the top of memory.
+fn count_leftover_bytes(data: &[u8], patterns: &[Pattern]) -> usize {
+    let list_start = (patterns.len() + 1) * 7;
+    let used = patterns.iter().map(|p| p.memory_size()).sum::<usize>();
+    let gap_end = (0x8000 - usize::from(PATTERN_DATA_START)).saturating_sub(used);
+
+    let unused_slots = data
+        [list_start.min(SERIALIZED_DATA_PATTERN_LIST_LENGTH)..SERIALIZED_DATA_PATTERN_LIST_LENGTH]
+        .iter();
+    let gap = data
+        [SERIALIZED_DATA_PATTERN_LIST_LENGTH..gap_end.max(SERIALIZED_DATA_PATTERN_LIST_LENGTH)]
+        .iter();
+
+    unused_slots.chain(gap).filter(|b| **b != 0).count()
+}
+
+/// Bytes of stitch data a pattern takes up in memory, not counting the memo
+fn stitch_data_size(width: u16, height: u16) -> usize {
+    ((f32::from(width) / 4.0).ceil() * f32::from(height) / 2.0).ceil() as usize
+}
+
+/// Find patterns whose header dimensions don't match the space up to the next pattern
+///
+/// Patterns are stored back to back, so the stitch data and memo of a pattern
+/// should exactly fill the gap between its offset and the next pattern's. A
+/// corrupt header makes the pattern read into its neighbor or leave a hole.
+fn find_inconsistent_patterns(data: &[u8]) -> Vec<u16> {
+    let mut extents = pattern_headers(data)
+        .map(|h| {
+            let size = stitch_data_size(h.width, h.height) + memo_size(h.height);
+            (usize::from(h.end_offset), size, h.number)
+        })
+        .collect::<Vec<_>>();
+    extents.sort_unstable_by_key(|(offset, _, _)| *offset);
+
+    let mut inconsistent = vec![];
+    for (i, (offset, size, number)) in extents.iter().copied().enumerate() {
+        let next_offset = match extents.get(i + 1) {
+            Some((next_offset, _, _)) => *next_offset,
+            None if offset + size <= PATTERN_DATA_END => continue,
+            None => PATTERN_DATA_END,
+        };
+
+        let available = next_offset.checked_sub(offset);
+        if available != Some(size) {
+            warn!(
+                pattern = number,
+                size, available, "Pattern size doesn't match its place in memory"
+            );
+            inconsistent.push(number);
+        }
+    }
+
+    inconsistent
+}
+
 fn pattern_data_sizes(width: u16, height: u16) -> (usize, usize, usize) {
     let row_nibbles = (f32::from(width) / 4.0).ceil() as usize;
     let row_pad_bits = util::padding(usize::from(width), 4);
@@ -338,41 +1289,88 @@ fn pattern_data_sizes(width: u16, height: u16) -> (usize, usize, usize) {
     (row_nibbles, row_pad_bits, initial_padding)
 }
 
+/// A row's bits as stored: padded on the left to whole nibbles, then right to left
+fn serialized_row_bits(row: &[bool], row_pad_bits: usize) -> impl Iterator<Item = bool> + '_ {
+    repeat_n(false, row_pad_bits).chain(row.iter().copied().rev())
+}
+
 fn parse_pattern_rows(width: u16, height: u16, data: &[u8]) -> Vec<Vec<bool>> {
     let (row_nibbles, row_pad_bits, initial_padding) = pattern_data_sizes(width, height);
 
-    let nibble_data = util::to_nibbles(data);
-
     (0..usize::from(height))
         .map(|row| {
             let start_index = initial_padding + row_nibbles * row;
-            let end_index = start_index + row_nibbles;
 
-            let bits = util::nibble_bits(&nibble_data[start_index..end_index]);
+            // Rows start mid-byte when the previous row had an odd number of nibbles
+            let nibbles = util::nibbles_iter(&data[start_index / 2..])
+                .skip(start_index % 2)
+                .take(row_nibbles);
 
-            bits[row_pad_bits..].iter().copied().rev().collect()
+            let mut bits = util::nibble_bits_iter(nibbles)
+                .skip(row_pad_bits)
+                .collect::<Vec<_>>();
+            bits.reverse();
+            bits
         })
         .collect()
 }
 
-fn serialize_pattern_layout(layout: &[(u16, &Pattern, Vec<u8>)]) -> Vec<u8> {
+/// Lay the patterns out back to back from the start of pattern memory, with their serialized data
+///
+/// Fails if there are more patterns or more pattern data than the machine holds.
+fn checked_pattern_layout(patterns: &[Pattern]) -> Result<Vec<(u16, &Pattern, Vec<u8>)>> {
+    if patterns.len() > MAX_PATTERNS {
+        return Err(KnittyError::TooManyPatterns {
+            count: patterns.len(),
+            max: MAX_PATTERNS,
+        });
+    }
+
+    let pattern_data = patterns
+        .iter()
+        .map(|p| p.serialize_data())
+        .collect::<Vec<_>>();
+
+    let required = pattern_data.iter().map(|d| d.len()).sum::<usize>();
+    let available = PATTERN_DATA_END - usize::from(PATTERN_DATA_START);
+    if required > available {
+        return Err(KnittyError::PatternMemoryFull {
+            required,
+            available,
+        });
+    }
+
+    let mut offset = PATTERN_DATA_START;
+    let mut layout = Vec::with_capacity(patterns.len());
+
+    for (pattern, data) in patterns.iter().zip(pattern_data) {
+        let data_len = data.len() as u16;
+        layout.push((offset, pattern, data));
+        offset += data_len;
+    }
+
+    Ok(layout)
+}
+
+fn serialize_pattern_layout(
+    layout: &[(u16, &Pattern, Vec<u8>)],
+    next_number: u16,
+) -> Result<Vec<u8>> {
     let mut data = vec![];
 
     for (offset, pattern, _) in layout {
-        data.extend(pattern.serialize_header(*offset));
+        data.extend(pattern.serialize_header(*offset)?);
     }
 
-    let max_number = layout.iter().map(|(_, p, _)| p.number).max().unwrap_or(900);
-
     data.extend([0, 0, 0, 0, 0]);
-    data.extend(util::from_nibbles(&util::to_bcd(max_number + 1, 4)));
+    data.extend(util::from_nibbles(&util::to_bcd_fixed(next_number, 4)?));
 
-    let pad_patterns = 97 - layout.len();
-    data.extend(repeat(0).take(pad_patterns * 7));
+    let pad_patterns = MAX_PATTERNS - layout.len();
+    data.extend(repeat_n(0, pad_patterns * 7));
 
     assert_eq!(data.len(), SERIALIZED_DATA_PATTERN_LIST_LENGTH);
 
-    data
+    Ok(data)
 }
 
 fn serialize_pattern_memory_padding(layout: &[(u16, &Pattern, Vec<u8>)]) -> Vec<u8> {
@@ -381,7 +1379,7 @@ fn serialize_pattern_memory_padding(layout: &[(u16, &Pattern, Vec<u8>)]) -> Vec<
     if let Some((end, _, data)) = layout.last() {
         last_pattern_end = *end as usize + data.len();
     } else {
-        last_pattern_end = 0x120;
+        last_pattern_end = PATTERN_DATA_START.into();
     }
 
     let pattern_pad = 0x8000 - last_pattern_end - SERIALIZED_DATA_PATTERN_LIST_LENGTH;
@@ -399,8 +1397,776 @@ fn serialize_pattern_memory(layout: &[(u16, &Pattern, Vec<u8>)]) -> Vec<u8> {
     data
 }
 
-fn serialize_loaded_pattern(pattern: u16) -> Vec<u8> {
+fn join_memory_regions(regions: &[MemoryRegion]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(MEMORY_SIZE);
+    for (_, bytes, _) in regions {
+        data.extend(*bytes);
+    }
+    data
+}
+
+/// Make sure serialized memory is exactly 32 KiB, blaming the first region that isn't the size it should be
+fn check_memory_size(data: &[u8], regions: &[MemoryRegion]) -> Result<()> {
+    if data.len() == MEMORY_SIZE {
+        return Ok(());
+    }
+
+    let (region, region_expected, region_actual) = regions
+        .iter()
+        .find(|(_, bytes, range)| bytes.len() != range.len())
+        .map_or(("unknown", 0, 0), |(name, bytes, range)| {
+            (*name, range.len(), bytes.len())
+        });
+
+    Err(KnittyError::SerializedSizeMismatch {
+        expected: MEMORY_SIZE,
+        actual: data.len(),
+        region,
+        region_expected,
+        region_actual,
+    })
+}
+
+fn serialize_loaded_pattern(pattern: u16) -> Result<Vec<u8>> {
     let mut nibbles = vec![Nibble::new(1)];
-    nibbles.extend(util::to_bcd(pattern, 3));
-    util::from_nibbles(&nibbles)
+    nibbles.extend(util::to_bcd_fixed(pattern, 3)?);
+    Ok(util::from_nibbles(&nibbles))
+}
+
+#[test]
+fn test_serialize_golden_image() {
+    // .XX...XX.
+    // XXXX.XXXX
+    // .XXXXXXX.
+    // ..XXXXX..
+    // ....X....
+    let image = image::load_from_memory(include_bytes!("../testdata/heart_9x5.png"))
+        .unwrap()
+        .to_luma8();
+    let pattern = Pattern::from_image(901, &image, &ImageOptions::default()).unwrap();
+
+    // One nibble of initial padding since the 5 rows of 3 nibbles add up
+    // to an odd number, then each row padded with 3 bits and reversed
+    assert_eq!(
+        pattern.serialize_data(),
+        &[0x00, 0xc6, 0x1e, 0xf0, 0xfe, 0x07, 0xc0, 0x10, 0, 0, 0]
+    );
+    assert_eq!(
+        pattern.serialize_header(0x0200).unwrap(),
+        &[0x02, 0x00, 0x00, 0x50, 0x09, 0x09, 0x01]
+    );
+}
+
+#[cfg(test)]
+fn test_pattern(number: u16, width: u16, height: u16) -> Pattern {
+    Pattern {
+        number,
+        rows: vec![vec![true; usize::from(width)]; usize::from(height)],
+        height,
+        width,
+        memo: vec![0; memo_size(height)],
+    }
+}
+
+#[test]
+fn test_empty_disk_control_data() {
+    let mut state = MachineState::empty();
+    state.serialize().unwrap();
+
+    let control_data = &state.control_data;
+    assert_eq!(control_data.next_pattern_ptr1, 0x120);
+    assert_eq!(control_data.next_pattern_ptr2, 0);
+    assert_eq!(control_data.last_pattern_end_ptr, 0);
+    assert_eq!(control_data.last_pattern_start_ptr, 0);
+    assert_eq!(control_data.header_end_ptr, 0x7ff9);
+}
+
+#[test]
+fn test_control_data_fields() {
+    let mut data = MachineState::empty().serialize().unwrap();
+    data[0x7f16] = 0xab;
+
+    let fields = MachineState::from_memory_dump(&data)
+        .unwrap()
+        .control_data_fields();
+    assert_eq!(fields[0], ("next_pattern_ptr1", 0x120));
+    assert_eq!(fields[7], ("header_end_ptr", 0x7ff9));
+    assert_eq!(fields[10], ("unknown4_2", 0xab));
+}
+
+#[test]
+fn test_serialize_empty() {
+    let data = MachineState::empty().serialize().unwrap();
+    assert_eq!(data.len(), 0x8000);
+
+    // No headers, just the terminator with the next pattern number in BCD
+    assert_eq!(&data[..7], &[0, 0, 0, 0, 0, 0x09, 0x01]);
+
+    let parsed = MachineState::from_memory_dump(&data).unwrap();
+    assert!(parsed.patterns().is_empty());
+    assert!(parsed.inconsistent_patterns().is_empty());
+    assert_eq!(parsed.next_pattern_number(), 901);
+
+    let control_data = &parsed.control_data;
+    assert_eq!(control_data.next_pattern_ptr1, 0x120);
+    assert_eq!(control_data.next_pattern_ptr2, 0);
+    assert_eq!(control_data.last_pattern_end_ptr, 0);
+    assert_eq!(control_data.last_pattern_start_ptr, 0);
+    assert_eq!(control_data.header_end_ptr, 0x7ff9);
+    control_data.validate().unwrap();
+}
+
+#[test]
+fn test_full_disk_control_data() {
+    let mut state = MachineState::empty();
+    for number in 901..901 + MAX_PATTERNS as u16 {
+        state.add_pattern(test_pattern(number, 8, 2));
+    }
+    state.serialize().unwrap();
+
+    // Each pattern is 2 bytes of stitches and 1 byte of memo
+    let control_data = &state.control_data;
+    assert_eq!(control_data.last_pattern_end_ptr, 0x120 + 96 * 3);
+    assert_eq!(control_data.last_pattern_start_ptr, 0x120 + 97 * 3);
+    assert_eq!(control_data.next_pattern_ptr1, 0x120 + 97 * 3 + 1);
+    assert_eq!(control_data.next_pattern_ptr2, 0x120 + 97 * 3 + 1);
+    assert_eq!(control_data.header_end_ptr, 0x8000 - 98 * 7);
+}
+
+#[test]
+fn test_invalid_control_data() {
+    let control_data = ControlData {
+        next_pattern_ptr1: 0x200,
+        next_pattern_ptr2: 0x200,
+        last_pattern_end_ptr: 0x180,
+        last_pattern_start_ptr: 0x120,
+        header_end_ptr: 0x7ff2,
+        ..Default::default()
+    };
+
+    assert!(control_data.validate().is_err());
+}
+
+#[test]
+fn test_serialize_pattern_memory_full() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(901, 200, 999));
+    state.serialize().unwrap();
+
+    state.add_pattern(test_pattern(902, 200, 999));
+    let error = state.serialize().unwrap_err();
+    assert!(matches!(
+        error,
+        KnittyError::PatternMemoryFull {
+            required: 50950,
+            available: 31794
+        }
+    ));
+    assert!(error.to_string().contains("19156 bytes over"));
+}
+
+#[test]
+fn test_try_add_pattern() {
+    let mut state = MachineState::empty();
+    state.try_add_pattern(test_pattern(901, 200, 999)).unwrap();
+    let free = state.free_pattern_memory();
+
+    let error = state
+        .try_add_pattern(test_pattern(902, 200, 999))
+        .unwrap_err();
+    assert!(matches!(error, KnittyError::PatternMemoryFull { .. }));
+    assert_eq!(state.patterns().len(), 1);
+    assert_eq!(state.free_pattern_memory(), free);
+
+    // Replacing the pattern frees its memory first
+    state.try_add_pattern(test_pattern(901, 200, 999)).unwrap();
+    state.try_add_pattern(test_pattern(902, 10, 10)).unwrap();
+    assert!(state.free_pattern_memory() < free);
+    state.serialize().unwrap();
+}
+
+#[test]
+fn test_from_bitmap() {
+    let pattern = Pattern::from_bitmap(901, vec![vec![true, false, true]; 3]).unwrap();
+    assert_eq!((pattern.width(), pattern.height()), (3, 3));
+    assert_eq!(pattern.memo(), &[0, 0]);
+    assert_eq!(pattern.memory_size(), pattern.serialize_data().len());
+
+    let error = Pattern::from_bitmap(901, vec![vec![true; 3], vec![true; 2]]).unwrap_err();
+    assert!(matches!(error, KnittyError::RaggedBitmap));
+
+    let error = Pattern::from_bitmap(901, vec![vec![true; 201]; 3]).unwrap_err();
+    assert!(matches!(
+        error,
+        KnittyError::PatternTooLarge {
+            dimension: "width",
+            size: 201,
+            ..
+        }
+    ));
+    let error = Pattern::from_bitmap(901, vec![vec![true; 3]; 1000]).unwrap_err();
+    assert!(matches!(
+        error,
+        KnittyError::PatternTooLarge {
+            dimension: "height",
+            size: 1000,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_origin_hint() {
+    let mut state = MachineState::empty();
+    let data = state.serialize().unwrap();
+    let parsed = MachineState::from_memory_dump(&data).unwrap();
+    assert_eq!(parsed.origin_hint(), Origin::Knitty2Created);
+
+    state.add_pattern(test_pattern(901, 8, 2));
+    state.data1[0] = 0x42;
+    let mut data = state.serialize().unwrap();
+    let parsed = MachineState::from_memory_dump(&data).unwrap();
+    assert_eq!(parsed.origin_hint(), Origin::Knitty2);
+
+    // A deleted pattern's stitches left behind in free memory
+    data[0x1000] = 0xff;
+    let parsed = MachineState::from_memory_dump(&data).unwrap();
+    assert_eq!(parsed.origin_hint(), Origin::Machine);
+}
+
+#[test]
+fn test_debug_rows() {
+    let rows = vec![vec![true, true, false, false, false, true], vec![false; 6]];
+    let pattern = Pattern::from_bitmap(901, rows).unwrap();
+
+    let lines = pattern.debug_rows();
+    let lines = lines.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("  0: 2X 3_ 1X "));
+    assert!(lines[0].ends_with(" 23"));
+    assert!(lines[1].starts_with("  1: 6_ "));
+    assert!(lines[1].ends_with(" 00"));
+}
+
+#[test]
+fn test_check_memory_size() {
+    let list = vec![0; PATTERN_LIST_END];
+    let rest = vec![0; 0x8000 - PATTERN_LIST_END - 1];
+    let regions: [MemoryRegion; 2] = [
+        ("pattern list", &list, 0..PATTERN_LIST_END),
+        ("machine data", &rest, PATTERN_LIST_END..0x8000),
+    ];
+
+    let data = join_memory_regions(&regions);
+    match check_memory_size(&data, &regions) {
+        Err(KnittyError::SerializedSizeMismatch {
+            actual,
+            region,
+            region_expected,
+            region_actual,
+            ..
+        }) => {
+            assert_eq!(actual, 0x7fff);
+            assert_eq!(region, "machine data");
+            assert_eq!(region_actual + 1, region_expected);
+        }
+        other => panic!("Expected a size mismatch, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_layout_report() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(903, 5, 3));
+    state.add_pattern(test_pattern(901, 8, 2));
+    state.serialize().unwrap();
+
+    // 8x2 is 2 bytes of stitches and 1 of memo, 5x3 is 3 and 2
+    let layout = state.layout_report();
+    assert_eq!(layout, [(901, 0x120, 3), (903, 0x123, 5)]);
+    assert_eq!(state.control_data.last_pattern_end_ptr, 0x123);
+}
+
+#[test]
+fn test_serialized_layout() {
+    let pattern = test_pattern(901, 5, 3);
+    let layout = pattern.serialized_layout();
+    assert_eq!(
+        layout,
+        SerializedLayout {
+            row_nibbles: 2,
+            row_pad_bits: 3,
+            initial_padding: 0,
+            stitch_data_len: 3,
+            memo_len: 2,
+        }
+    );
+    assert_eq!(
+        layout.stitch_data_len + layout.memo_len,
+        pattern.serialize_data().len()
+    );
+
+    let layout = test_pattern(901, 1, 3).serialized_layout();
+    assert_eq!((layout.row_nibbles, layout.row_pad_bits), (1, 3));
+    assert_eq!((layout.initial_padding, layout.stitch_data_len), (1, 2));
+}
+
+#[test]
+fn test_short_memory_dump() {
+    assert!(matches!(
+        MachineState::from_memory_dump(&[0; 0x4000]),
+        Err(KnittyError::MemorySizeMismatch {
+            expected: MEMORY_SIZE,
+            actual: 0x4000
+        })
+    ));
+    assert!(matches!(
+        MachineState::from_memory_dump(&[0; MEMORY_SIZE + 1]),
+        Err(KnittyError::MemorySizeMismatch {
+            expected: MEMORY_SIZE,
+            actual: 0x8001
+        })
+    ));
+
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(901, 4, 4));
+    let data = state.serialize().unwrap();
+
+    let truncated = iter_patterns(&data[..0x4000]).collect::<Vec<_>>();
+    assert!(matches!(
+        truncated[..],
+        [Err(KnittyError::PatternOutOfBounds { index: 0 })]
+    ));
+    assert_eq!(iter_patterns(&data[..3]).count(), 0);
+}
+
+#[test]
+fn test_image_size_limits() {
+    let options = ImageOptions::default();
+
+    let widest = GrayImage::new(u32::from(MAX_WIDTH), 1);
+    assert_eq!(
+        Pattern::from_image(901, &widest, &options).unwrap().width(),
+        200
+    );
+
+    let too_wide = GrayImage::new(201, 1);
+    assert!(matches!(
+        Pattern::from_image(901, &too_wide, &options),
+        Err(KnittyError::PatternTooLarge {
+            dimension: "width",
+            size: 201,
+            max: MAX_WIDTH
+        })
+    ));
+
+    let too_high = GrayImage::new(1, 1000);
+    assert!(matches!(
+        Pattern::from_image(901, &too_high, &options),
+        Err(KnittyError::PatternTooLarge {
+            dimension: "height",
+            size: 1000,
+            max: MAX_HEIGHT
+        })
+    ));
+}
+
+#[test]
+fn test_threshold_inclusive() {
+    let image = GrayImage::from_raw(3, 1, vec![127, 128, 129]).unwrap();
+
+    let exclusive = Pattern::from_image(901, &image, &ImageOptions::default()).unwrap();
+    assert_eq!(exclusive.rows(), &[vec![true, false, false]]);
+
+    let options = ImageOptions {
+        threshold_inclusive: true,
+        ..ImageOptions::default()
+    };
+    let inclusive = Pattern::from_image(901, &image, &options).unwrap();
+    assert_eq!(inclusive.rows(), &[vec![true, true, false]]);
+}
+
+#[test]
+fn test_dither() {
+    // A light gradient that a flat threshold turns completely blank
+    let image = GrayImage::from_fn(64, 16, |x, _| [140 + x as u8].into());
+    let count_selected = |pattern: &Pattern| {
+        pattern
+            .rows()
+            .iter()
+            .flatten()
+            .filter(|selected| **selected)
+            .count()
+    };
+
+    let flat = Pattern::from_image(901, &image, &ImageOptions::default()).unwrap();
+    assert_eq!(count_selected(&flat), 0);
+
+    let options = ImageOptions {
+        dither: true,
+        ..ImageOptions::default()
+    };
+    let dithered = Pattern::from_image(901, &image, &options).unwrap();
+    let selected = count_selected(&dithered);
+    assert!(
+        (250..400).contains(&selected),
+        "{selected} stitches selected"
+    );
+
+    // Darker columns on the left get more stitches than lighter ones on the right
+    let column = |x: usize| dithered.rows().iter().filter(|row| row[x]).count();
+    assert!((0..16).map(column).sum::<usize>() > (48..64).map(column).sum::<usize>());
+}
+
+#[test]
+fn test_invert() {
+    let pattern = test_pattern(901, 5, 3);
+    let options = ImageOptions {
+        invert: true,
+        ..ImageOptions::default()
+    };
+
+    let mut image = pattern.to_image();
+    image::imageops::invert(&mut image);
+    assert_eq!(
+        image.get_pixel(0, 0)[0],
+        255 - pattern.to_image().get_pixel(0, 0)[0]
+    );
+
+    let round_trip = Pattern::from_image(901, &image, &options).unwrap();
+    assert_eq!(round_trip, pattern);
+}
+
+#[test]
+fn test_set_memo() {
+    let mut pattern = test_pattern(901, 4, 5);
+
+    pattern.set_memo(vec![1, 2, 3]).unwrap();
+    assert_eq!(pattern.memo(), &[1, 2, 3]);
+
+    assert!(matches!(
+        pattern.set_memo(vec![1, 2]),
+        Err(KnittyError::MemoSizeMismatch {
+            expected: 3,
+            actual: 2
+        })
+    ));
+    assert_eq!(pattern.memo(), &[1, 2, 3]);
+}
+
+#[test]
+fn test_rebuild_control_data() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(901, 8, 2));
+    let mut data = state.serialize().unwrap();
+
+    // Corrupt the next pattern and last pattern start pointers
+    data[0x7f00..0x7f02].copy_from_slice(&[0x12, 0x34]);
+    data[0x7f0a..0x7f0c].copy_from_slice(&[0x00, 0x00]);
+    let mut parsed = MachineState::from_memory_dump(&data).unwrap();
+    assert!(parsed.control_data.validate().is_err());
+
+    assert!(parsed.rebuild_control_data().unwrap());
+    assert_eq!(parsed.control_data, state.control_data);
+    assert!(!parsed.rebuild_control_data().unwrap());
+}
+
+#[test]
+fn test_next_pattern_number() {
+    let mut state = MachineState::empty();
+    assert_eq!(state.next_pattern_number(), 901);
+
+    state.add_pattern(test_pattern(905, 4, 4));
+    assert_eq!(state.next_pattern_number(), 906);
+
+    state.set_next_pattern_number(Some(950));
+    assert_eq!(state.next_pattern_number(), 950);
+
+    let data = state.serialize().unwrap();
+    assert_eq!(&data[7..14], &[0, 0, 0, 0, 0, 0x09, 0x50]);
+
+    let mut parsed = MachineState::from_memory_dump(&data).unwrap();
+    assert_eq!(parsed.next_pattern_number(), 950);
+    assert_eq!(parsed.serialize().unwrap(), data);
+
+    // Taking the overridden number goes back to the default
+    parsed.add_pattern(test_pattern(950, 4, 4));
+    assert_eq!(parsed.next_pattern_number(), 951);
+
+    state.set_next_pattern_number(None);
+    let parsed = MachineState::from_memory_dump(&state.serialize().unwrap()).unwrap();
+    assert_eq!(parsed.next_pattern_number, None);
+}
+
+#[test]
+fn test_set_loaded_pattern() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(905, 4, 4));
+
+    assert!(state.set_loaded_pattern(905));
+    assert_eq!(state.loaded_pattern(), 905);
+
+    let data = state.serialize().unwrap();
+    assert_eq!(loaded_pattern_number(&data), 905);
+
+    assert!(state.set_loaded_pattern(12));
+    assert_eq!(state.loaded_pattern(), 12);
+
+    assert!(!state.set_loaded_pattern(906));
+    assert_eq!(state.loaded_pattern(), DEFAULT_LOADED_PATTERN);
+}
+
+#[test]
+fn test_remove_pattern() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(901, 4, 4));
+    state.add_pattern(test_pattern(902, 4, 4));
+    state.set_loaded_pattern(902);
+
+    assert!(state.remove_pattern(901));
+    assert!(!state.remove_pattern(901));
+    assert_eq!(state.loaded_pattern(), 902);
+
+    assert!(state.remove_pattern(902));
+    assert!(state.patterns().is_empty());
+    assert_eq!(state.loaded_pattern(), DEFAULT_LOADED_PATTERN);
+}
+
+#[test]
+fn test_renumber_pattern() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(901, 4, 4));
+    state.add_pattern(test_pattern(905, 6, 2));
+    state.set_loaded_pattern(901);
+
+    state.renumber_pattern(901, 910).unwrap();
+    let numbers = state
+        .patterns()
+        .iter()
+        .map(|p| p.pattern_number())
+        .collect::<Vec<_>>();
+    assert_eq!(numbers, &[905, 910]);
+    assert_eq!(
+        state.pattern(910).unwrap().rows(),
+        test_pattern(910, 4, 4).rows()
+    );
+    assert_eq!(state.loaded_pattern(), 910);
+
+    assert!(matches!(
+        state.renumber_pattern(910, 905),
+        Err(KnittyError::PatternNumberTaken(905))
+    ));
+    assert!(matches!(
+        state.renumber_pattern(901, 902),
+        Err(KnittyError::PatternNotFound(901))
+    ));
+    assert_eq!(state.patterns().len(), 2);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(901, 5, 3));
+    state.add_pattern(test_pattern(902, 9, 4));
+    state.add_pattern(
+        Pattern::from_bitmap(903, vec![vec![true, false, true, false, true]]).unwrap(),
+    );
+    let mut data = state.serialize().unwrap();
+
+    // Machine data and an unknown control data field knitty2 never writes itself
+    data[0x7ef0] = 0x34;
+    data[0x7f02] = 0x12;
+    let parsed = MachineState::from_memory_dump(&data).unwrap();
+
+    let json = serde_json::to_value(&parsed).unwrap();
+    assert_eq!(json["patterns"][2]["rows"][0], "X_X_X");
+    assert_eq!(json["control_data"]["unknown1"], 0x1200);
+
+    let mut round_trip: MachineState = serde_json::from_value(json).unwrap();
+    assert_eq!(round_trip, parsed);
+    assert_eq!(round_trip.serialize().unwrap(), data);
+
+    let mut pattern = serde_json::to_value(test_pattern(901, 5, 3)).unwrap();
+    pattern["width"] = 6.into();
+    assert!(serde_json::from_value::<Pattern>(pattern).is_err());
+}
+
+#[test]
+fn test_content_checksum() {
+    let mut a = test_pattern(901, 5, 3);
+    let b = test_pattern(902, 5, 3);
+    let c = test_pattern(903, 3, 5);
+    assert_eq!(a.content_checksum(), b.content_checksum());
+    assert_ne!(a.content_checksum(), c.content_checksum());
+
+    a.memo[0] = 1;
+    assert_eq!(a.content_checksum(), b.content_checksum());
+
+    a.rows[1][2] = false;
+    assert_ne!(a.content_checksum(), b.content_checksum());
+}
+
+#[test]
+fn test_add_pattern_sorts() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(905, 4, 4));
+    state.add_pattern(test_pattern(901, 4, 4));
+    state.add_pattern(test_pattern(903, 4, 4));
+
+    let numbers = state
+        .patterns()
+        .iter()
+        .map(|p| p.pattern_number())
+        .collect::<Vec<_>>();
+    assert_eq!(numbers, &[901, 903, 905]);
+}
+
+#[test]
+fn test_add_pattern_replaces_duplicate() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(901, 4, 4));
+    state.add_pattern(test_pattern(902, 4, 4));
+    state.add_pattern(test_pattern(901, 10, 2));
+
+    assert_eq!(state.patterns().len(), 2);
+
+    let replaced = state.pattern(901).unwrap();
+    assert_eq!((replaced.width(), replaced.height()), (10, 2));
+    assert_eq!(replaced.rows(), test_pattern(901, 10, 2).rows());
+}
+
+#[test]
+fn test_resize_canvas() {
+    let image = GrayImage::from_raw(2, 2, vec![0, 255, 255, 0]).unwrap();
+    let mut pattern = Pattern::from_image(901, &image, &ImageOptions::default()).unwrap();
+
+    pattern.resize_canvas(3, 5);
+    assert_eq!((pattern.width(), pattern.height()), (3, 5));
+    assert_eq!(pattern.memo().len(), 3);
+    assert_eq!(
+        pattern.rows(),
+        &[
+            vec![false, false, false],
+            vec![false, false, false],
+            vec![false, false, false],
+            vec![true, false, false],
+            vec![false, true, false],
+        ]
+    );
+
+    pattern.resize_canvas(1, 1);
+    assert_eq!(pattern.rows(), &[vec![false]]);
+    assert_eq!(pattern.memo().len(), 1);
+}
+
+#[test]
+fn test_place_on_canvas() {
+    let image = GrayImage::from_raw(2, 1, vec![0, 255]).unwrap();
+    let mut pattern = Pattern::from_image(901, &image, &ImageOptions::default()).unwrap();
+
+    pattern.place_on_canvas(5, true).unwrap();
+    assert_eq!(pattern.width(), 5);
+    assert_eq!(pattern.rows(), &[vec![false, true, false, false, false]]);
+
+    pattern.place_on_canvas(6, false).unwrap();
+    assert_eq!(
+        pattern.rows(),
+        &[vec![false, true, false, false, false, false]]
+    );
+
+    assert!(matches!(
+        pattern.place_on_canvas(4, true),
+        Err(KnittyError::WiderThanCanvas {
+            width: 6,
+            canvas: 4
+        })
+    ));
+}
+
+#[test]
+fn test_find_inconsistent_patterns() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(901, 8, 2));
+    state.add_pattern(test_pattern(902, 8, 2));
+    let mut data = state.serialize().unwrap();
+
+    assert!(find_inconsistent_patterns(&data).is_empty());
+
+    // Move the second pattern one byte into the first
+    let offset = u16::from_be_bytes([data[7], data[8]]) - 1;
+    data[7..9].copy_from_slice(&offset.to_be_bytes());
+
+    let state = MachineState::from_memory_dump(&data).unwrap();
+    assert_eq!(state.inconsistent_patterns(), &[901]);
+}
+
+#[test]
+fn test_find_inconsistent_patterns_past_pattern_data() {
+    // A 1x1 pattern that claims to end at 0x7e00, past the pattern data
+    let mut data = vec![0; MEMORY_SIZE];
+    data[..7].copy_from_slice(&[0x7e, 0x00, 0x00, 0x10, 0x01, 0x09, 0x01]);
+
+    let state = MachineState::from_memory_dump(&data).unwrap();
+    assert_eq!(state.inconsistent_patterns(), &[901]);
+}
+
+#[test]
+fn test_empty_matches_blank_memory() {
+    let mut empty = MachineState::empty();
+    let mut blank = MachineState::from_memory_dump(&[0; 0x8000]).unwrap();
+    blank.clear_patterns();
+
+    assert_eq!(empty.serialize().unwrap(), blank.serialize().unwrap());
+}
+
+#[test]
+fn test_mirror() {
+    let image = GrayImage::from_raw(4, 1, vec![0, 0, 0, 255]).unwrap();
+
+    let pattern = Pattern::from_image(901, &image, &ImageOptions::default()).unwrap();
+    assert_eq!(pattern.rows(), &[vec![true, true, true, false]]);
+    assert_eq!(pattern.serialize_data(), vec![0x07, 0x00]);
+
+    let options = ImageOptions {
+        mirror: true,
+        ..ImageOptions::default()
+    };
+    let mirrored = Pattern::from_image(901, &image, &options).unwrap();
+    assert_eq!(mirrored.rows(), &[vec![false, true, true, true]]);
+    assert_eq!(mirrored.serialize_data(), vec![0x0e, 0x00]);
+}
+
+#[test]
+fn test_to_ascii() {
+    let image = GrayImage::from_raw(3, 2, vec![0, 255, 0, 255, 255, 0]).unwrap();
+    let pattern = Pattern::from_image(901, &image, &ImageOptions::default()).unwrap();
+
+    assert_eq!(pattern.to_ascii(), "X_X\n__X\n");
+}
+
+#[test]
+fn test_serialize_oversized_number() {
+    let mut state = MachineState::empty();
+    state.add_pattern(test_pattern(10000, 2, 2));
+
+    assert!(matches!(
+        state.serialize(),
+        Err(KnittyError::BcdOverflow {
+            value: 10000,
+            width: 4
+        })
+    ));
+}
+
+#[test]
+fn test_equals_ignoring_memo() {
+    let pattern = test_pattern(901, 4, 4);
+    let mut with_memo = test_pattern(901, 4, 4);
+    with_memo.memo[0] = 0x12;
+
+    assert_ne!(pattern, with_memo);
+    assert!(pattern.equals_ignoring_memo(&with_memo));
+    assert!(!pattern.equals_ignoring_memo(&test_pattern(902, 4, 4)));
+    assert!(!pattern.equals_ignoring_memo(&test_pattern(901, 4, 2)));
 }
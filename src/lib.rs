@@ -0,0 +1,21 @@
+//! Read and write Brother KH-940 knitting machine memory, and emulate the
+//! floppy drive it saves to
+//!
+//! The `knitty2` command line tool is a thin layer over this library. The
+//! main entry points are [`Disk`] for floppy disk images, [`MachineState`]
+//! for the machine memory stored on them, and [`Pattern`] for the patterns
+//! in that memory.
+
+pub mod capture;
+pub mod error;
+pub mod fdcemu;
+pub mod interop;
+pub mod kh940;
+pub mod nibble;
+pub mod render;
+pub mod util;
+
+pub use error::{KnittyError, Result};
+pub use fdcemu::Disk;
+pub use kh940::{MachineState, Pattern};
+pub use nibble::Nibble;
@@ -1,75 +1,808 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::PoisonError,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use clap::{Parser, Subcommand};
-use eyre::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use eyre::{bail, eyre, Context, Result};
+use image::{DynamicImage, GrayImage, ImageOutputFormat};
+use serial::SystemPort;
 
-mod fdcemu;
-mod kh940;
-mod nibble;
-mod util;
+use knitty2::{
+    capture::{ReplayPort, TracedPort},
+    fdcemu::{self, Disk, DiskStorage, FdcEvent, FdcMode, FdcServer},
+    interop,
+    kh940::{self, ImageOptions, MachineState, Pattern},
+    render::{self, ChartNumbering, Gauge},
+};
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::{filter::Targets, prelude::*};
 
-use fdcemu::{Disk, FdcServer};
-use kh940::{MachineState, Pattern};
-pub use nibble::Nibble;
+use crate::{config::Config, manifest::Manifest};
+
+mod config;
+mod manifest;
+mod ports;
+mod samples;
+#[cfg(feature = "gui")]
+mod view;
+
+/// File format of individual patterns in an import/export folder
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum PatternFormat {
+    /// Black and white images, one pixel per stitch
+    Png,
+    /// Two color images for the AYAB software
+    Ayab,
+}
 
 #[derive(Subcommand)]
 enum Command {
     /// Emulate being a floppy drive on a USB->FTDI port
-    Emulate { port: PathBuf, disk: PathBuf },
+    ///
+    /// The port can be left out when it's set in the config file or through
+    /// the KNITTY2_PORT environment variable.
+    #[command(override_usage = "knitty2 emulate [OPTIONS] [PORT] <DISK>")]
+    Emulate {
+        /// Serial port, or the disk image when the port is configured elsewhere
+        #[arg(value_name = "PORT")]
+        port_or_disk: PathBuf,
+
+        /// Disk image, created if it does not exist
+        #[arg(value_name = "DISK")]
+        disk: Option<PathBuf>,
+
+        /// Serial baud rate [default: 9600]
+        #[arg(long, env = "KNITTY2_BAUD")]
+        baud: Option<usize>,
+
+        /// Give up waiting for the machine after this many seconds [default: 3600]
+        #[arg(long, env = "KNITTY2_TIMEOUT")]
+        timeout_secs: Option<u64>,
+
+        /// Append a JSON line per sector written by the machine to this file
+        #[arg(long)]
+        session_log: Option<PathBuf>,
+
+        /// Write every byte sent or received on the serial port to this file,
+        /// replacing its contents
+        #[arg(long)]
+        trace_file: Option<PathBuf>,
+
+        /// Wait this long before answering each sector command, for machines that need slower replies
+        #[arg(long, default_value_t = 0)]
+        response_delay_ms: u64,
+
+        /// Print each sector the machine reads or writes as it happens
+        #[arg(long)]
+        progress: bool,
+
+        /// Exit after the machine has connected and switched to FDC mode, to test the cabling
+        #[arg(long)]
+        init_only: bool,
+
+        /// Accept the machine's writes but never save them to the disk image
+        #[arg(long)]
+        ephemeral: bool,
+
+        /// Print the number of patterns on the disk every this many seconds while emulating
+        #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+        status_every: Option<u64>,
+    },
+
+    /// Play the machine's side of an `emulate --trace-file` capture back to the emulator
+    ///
+    /// Checks that the emulator answers every command the way it did when the
+    /// capture was recorded. The disk image is only read, and should be the
+    /// one the capture started with.
+    Replay {
+        #[arg(value_name = "DISK")]
+        disk: PathBuf,
+
+        /// Trace file written by `emulate --trace-file`
+        capture: PathBuf,
+    },
+
+    /// List serial ports that look like USB serial adapters
+    ListPorts,
 
     /// Extract images from a disk image into a folder
-    Export { disk: PathBuf, target: PathBuf },
+    Export {
+        disk: PathBuf,
+
+        /// Folder to write the patterns to, or `-` to write the single --pattern to stdout
+        target: PathBuf,
+
+        /// Write into a non-empty target folder without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Render each stitch as a <w>:<h> pixel block to approximate fabric proportions
+        #[arg(long, default_value_t = Gauge::SQUARE)]
+        gauge: Gauge,
+
+        /// File format to write patterns in
+        #[arg(long, value_enum, default_value_t = PatternFormat::Png)]
+        format: PatternFormat,
+
+        /// Label the needles each column is knitted on above PNG charts
+        #[arg(long)]
+        needle_ruler: bool,
+
+        /// Number the rows and columns of PNG charts for hand-knitting, counting in this order
+        #[arg(long, value_enum, conflicts_with = "needle_ruler")]
+        chart_numbers: Option<ChartNumbering>,
+
+        /// Needles to the right of the bed's center the pattern is placed at
+        #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+        center_offset: i32,
+
+        /// Only export these pattern numbers, repeat the flag or separate with commas
+        #[arg(long = "pattern", value_delimiter = ',')]
+        patterns: Vec<u16>,
+
+        /// Write a `<n>.memo` file for every image, not just for patterns with a memo
+        #[arg(long)]
+        always_memo: bool,
+
+        /// Skip patterns whose stitches match the `<n>.png` in this folder
+        ///
+        /// The reference images are compared one pixel per stitch, so they
+        /// should be plain exports without a gauge, ruler or chart numbers.
+        /// Patterns with no readable reference image are always written.
+        #[arg(long, value_name = "FOLDER")]
+        changed_since: Option<PathBuf>,
+
+        /// Draw selected stitches white on black in PNG images instead of black on white
+        #[arg(long)]
+        invert: bool,
+    },
 
     /// Import images from a folder into a disk image ready for emulation
-    Import { disk: PathBuf, source: PathBuf },
+    Import {
+        /// Disk image to import into, or `-` to read it from stdin and write the result to stdout
+        disk: PathBuf,
+        source: PathBuf,
+
+        /// Overwrite the disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Save the patterns that could be read even if some files failed
+        #[arg(long)]
+        keep_partial: bool,
+
+        /// Pixels darker than this become selected stitches [default: 128]
+        ///
+        /// A pixel exactly at the threshold is not selected unless
+        /// --threshold-inclusive is given.
+        #[arg(long, env = "KNITTY2_THRESHOLD")]
+        threshold: Option<u8>,
+
+        /// Also select stitches for pixels exactly at the threshold
+        #[arg(long)]
+        threshold_inclusive: bool,
+
+        /// Snap pixels to multiples of this value before thresholding, for reproducible results
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+        quantize: Option<u8>,
+
+        /// Flip patterns left to right so the knit side of the fabric matches the image
+        ///
+        /// Without this the image is laid out as seen from the front of the
+        /// machine, which is the purl side of the fabric.
+        #[arg(long)]
+        mirror: bool,
+
+        /// Dither images before thresholding, for photographs and other shaded images
+        #[arg(long)]
+        dither: bool,
+
+        /// Select stitches for light pixels instead of dark ones, the inverse of export --invert
+        #[arg(long)]
+        invert: bool,
+
+        /// Pad patterns shorter than this with blank rows on top
+        ///
+        /// The machine has no documented minimum height, but patterns of only
+        /// one or two rows have been seen to misbehave while knitting.
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..=i64::from(kh940::MAX_HEIGHT)))]
+        min_height: Option<u16>,
+
+        /// Pad patterns with blank columns on the right to this many stitches
+        ///
+        /// Patterns wider than this fail to import.
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..=i64::from(kh940::MAX_WIDTH)))]
+        canvas_width: Option<u16>,
+
+        /// Center patterns on the --canvas-width canvas instead of keeping them at the left
+        #[arg(long, requires = "canvas_width")]
+        center: bool,
+
+        /// Pattern number the machine should suggest next, instead of the highest number plus one
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..=9999))]
+        next_number: Option<u16>,
+
+        /// Pattern number for the first page of multi-page TIFFs, counting up per page
+        ///
+        /// `.tif` and `.tiff` files are imported whatever `--format` is, in
+        /// file name order.
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..=9999))]
+        start_number: Option<u16>,
+
+        /// File format of the patterns to read, other files are ignored
+        #[arg(long, value_enum, default_value_t = PatternFormat::Png)]
+        format: PatternFormat,
+
+        /// Copy the disk image to `<DISK>.<unix time in ms>.bak` before overwriting it
+        #[arg(long)]
+        snapshot: bool,
+
+        /// Number of snapshots to keep, older ones are deleted
+        #[arg(long, default_value_t = 10, requires = "snapshot", value_parser = clap::value_parser!(u16).range(1..))]
+        keep_snapshots: u16,
+    },
+
+    /// Import a single file into a disk image as the given pattern number, whatever its name
+    ImportOne {
+        disk: PathBuf,
+        source: PathBuf,
+
+        /// Pattern number to store the file as, replacing any pattern with that number
+        #[arg(value_parser = clap::value_parser!(u16).range(1..=9999))]
+        number: u16,
+
+        /// Overwrite the disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Pixels darker than this become selected stitches [default: 128]
+        ///
+        /// A pixel exactly at the threshold is not selected unless
+        /// --threshold-inclusive is given.
+        #[arg(long, env = "KNITTY2_THRESHOLD")]
+        threshold: Option<u8>,
+
+        /// Also select stitches for pixels exactly at the threshold
+        #[arg(long)]
+        threshold_inclusive: bool,
+
+        /// Snap pixels to multiples of this value before thresholding, for reproducible results
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+        quantize: Option<u8>,
+
+        /// Flip the pattern left to right so the knit side of the fabric matches the image
+        #[arg(long)]
+        mirror: bool,
+
+        /// Dither the image before thresholding, for photographs and other shaded images
+        #[arg(long)]
+        dither: bool,
+
+        /// Select stitches for light pixels instead of dark ones, the inverse of export --invert
+        #[arg(long)]
+        invert: bool,
+
+        /// File format of the source file
+        #[arg(long, value_enum, default_value_t = PatternFormat::Png)]
+        format: PatternFormat,
+    },
+
+    /// Find patterns with identical stitches and remove all but the lowest numbered copy
+    Dedupe {
+        disk: PathBuf,
+
+        /// Only list the groups of duplicates, don't change the disk
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Only treat patterns as duplicates if their memos are identical too
+        #[arg(long)]
+        include_memo: bool,
+
+        /// Overwrite the disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Show information about a disk image
+    Info {
+        disk: PathBuf,
+
+        /// List the offset and size in memory of every pattern
+        #[arg(long)]
+        layout: bool,
+
+        /// Print every control data field, including the ones whose meaning is unknown
+        #[arg(long)]
+        control_data: bool,
+    },
+
+    /// List the patterns on a disk image with their sizes
+    List { disk: PathBuf },
+
+    /// Compare the patterns on a disk image with a folder of PNGs
+    ///
+    /// Every pattern is listed as unchanged, modified, new (only in the folder)
+    /// or disk-only.
+    Status {
+        disk: PathBuf,
+        source: PathBuf,
+
+        /// Pixels darker than this become selected stitches [default: 128]
+        ///
+        /// A pixel exactly at the threshold is not selected unless
+        /// --threshold-inclusive is given.
+        #[arg(long, env = "KNITTY2_THRESHOLD")]
+        threshold: Option<u8>,
+
+        /// Also select stitches for pixels exactly at the threshold
+        #[arg(long)]
+        threshold_inclusive: bool,
+    },
+
+    /// Page through the patterns on a disk image in the terminal
+    Browse { disk: PathBuf },
+
+    /// Print a single pattern from a disk image as text
+    Show {
+        disk: PathBuf,
+        pattern: u16,
+
+        /// Print each row's stitch run lengths and stored nibbles instead
+        #[arg(long)]
+        debug: bool,
+    },
+
+    /// Page through the patterns on a disk image in a window
+    #[cfg(feature = "gui")]
+    View { disk: PathBuf },
+
+    /// Save a picture of a pattern as it would appear on the machine's display
+    Screenshot {
+        disk: PathBuf,
+        pattern: u16,
+        output: PathBuf,
+    },
+
+    /// Check a disk image for patterns that don't fit their place in memory
+    Verify { disk: PathBuf },
+
+    /// Check a folder of patterns for problems before importing it
+    Lint {
+        source: PathBuf,
+
+        /// File format of the patterns to check, other files are ignored
+        #[arg(long, value_enum, default_value_t = PatternFormat::Png)]
+        format: PatternFormat,
+    },
+
+    /// Create a blank, formatted disk image with no patterns
+    #[command(alias = "new")]
+    Create {
+        disk: PathBuf,
+
+        /// Overwrite an existing disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Create a disk image filled with generated test patterns of varied kinds and sizes
+    ///
+    /// Checkerboards, diagonal stripes, dithered gradients and number labels
+    /// are generated in turn, including odd widths and heights. Patterns that
+    /// don't fit in the machine's memory are left out.
+    GenerateSamples {
+        disk: PathBuf,
+
+        /// Number of patterns to generate
+        #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u16).range(1..=kh940::MAX_PATTERNS as i64))]
+        count: u16,
+
+        /// Overwrite an existing disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Write the 12 byte IDs of all sectors in a disk image to a file
+    ExtractIds { disk: PathBuf, out: PathBuf },
+
+    /// Replace the sector IDs of a disk image with ones from `extract-ids`, keeping its data
+    ApplyIds {
+        disk: PathBuf,
+        ids: PathBuf,
+
+        /// Overwrite the disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Recompute the memory pointers of a disk image from its patterns
+    ///
+    /// This rescues disks the machine refuses because of corrupt pointers,
+    /// as long as the pattern list and the patterns themselves are intact.
+    Repair {
+        disk: PathBuf,
+
+        /// Overwrite the disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Remove a single pattern from a disk image
+    Delete {
+        disk: PathBuf,
+        pattern: u16,
+
+        /// Overwrite the disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Give a pattern on a disk image a different number
+    Renumber {
+        disk: PathBuf,
+        from: u16,
+
+        /// New number for the pattern, which must not be taken by another pattern
+        #[arg(value_parser = clap::value_parser!(u16).range(1..=9999))]
+        to: u16,
+
+        /// Overwrite the disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Remove all patterns from a disk image, keeping its other machine data
+    Clear {
+        disk: PathBuf,
+
+        /// Overwrite the disk image without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// List the patterns in a disk image matching all the given criteria
+    Find {
+        disk: PathBuf,
+
+        /// Only patterns exactly this many stitches wide
+        #[arg(long)]
+        width: Option<u16>,
+
+        /// Only patterns exactly this many rows high
+        #[arg(long)]
+        height: Option<u16>,
+
+        /// Only patterns with exactly the same stitches as this image
+        #[arg(long)]
+        matches: Option<PathBuf>,
+    },
 }
 
 #[derive(Parser)]
 struct Args {
     #[command(subcommand)]
     command: Command,
+
+    /// Read option defaults from this TOML file instead of ./knitty2.toml
+    #[arg(long, global = true, env = "KNITTY2_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Only print warnings and errors, besides what a command was asked to report
+    #[arg(long, short, global = true)]
+    quiet: bool,
 }
 
 fn main() -> Result<()> {
     dotenv::dotenv().ok();
-    tracing_subscriber::fmt::init();
 
     let args = Args::parse();
+    let filter = if args.quiet {
+        Targets::new().with_default(Level::WARN)
+    } else {
+        std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|targets| targets.parse().ok())
+            .unwrap_or_else(|| Targets::new().with_default(Level::INFO))
+    };
+    // Log to stderr, so stdout only has what the command outputs
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(filter)
+        .init();
+
+    let config = Config::load(args.config.as_deref())?;
 
     match args.command {
-        Command::Emulate { port, disk } => {
-            let port =
-                serial::open(&port).context(format!("Could not open serial port at {port:?}"))?;
-            let mut fdc_server = FdcServer::new(&disk, port)?;
+        Command::Emulate {
+            port_or_disk,
+            disk,
+            baud,
+            timeout_secs,
+            session_log,
+            trace_file,
+            response_delay_ms,
+            progress,
+            init_only,
+            ephemeral,
+            status_every,
+        } => {
+            let (port, disk) = match disk {
+                Some(disk) => (port_or_disk, disk),
+                None => {
+                    let port = std::env::var_os("KNITTY2_PORT")
+                        .map(PathBuf::from)
+                        .or(config.port)
+                        .ok_or_else(|| {
+                            eyre!("No serial port given on the command line, in KNITTY2_PORT, or in the config file")
+                        })?;
+                    (port, port_or_disk)
+                }
+            };
+            check_writable(&disk)?;
+            let baud_rate =
+                fdcemu::baud_rate(baud.or(config.baud).unwrap_or(fdcemu::DEFAULT_BAUD_RATE))?;
+            let timeout = timeout_secs
+                .or(config.timeout)
+                .map_or(fdcemu::DEFAULT_TIMEOUT, Duration::from_secs);
 
-            fdc_server.run()?;
+            let mut port = TracedPort::new(
+                serial::open(&port).context(format!("Could not open serial port at {port:?}"))?,
+            );
+            if let Some(trace_file) = trace_file {
+                port.set_trace_file(&trace_file)
+                    .context(format!("Could not open trace file at {trace_file:?}"))?;
+            }
+            if let Some(status_secs) = status_every {
+                let fdc_server = FdcServer::new_shared(&disk, port, baud_rate, timeout)?;
+                let handle = fdc_server.disk_handle();
+                std::thread::spawn(move || loop {
+                    std::thread::sleep(Duration::from_secs(status_secs));
+                    log_disk_status(&handle.lock().unwrap_or_else(PoisonError::into_inner));
+                });
+
+                serve(
+                    fdc_server,
+                    session_log,
+                    response_delay_ms,
+                    progress,
+                    init_only,
+                    ephemeral,
+                )?;
+            } else {
+                let fdc_server = FdcServer::new(&disk, port, baud_rate, timeout)?;
+                serve(
+                    fdc_server,
+                    session_log,
+                    response_delay_ms,
+                    progress,
+                    init_only,
+                    ephemeral,
+                )?;
+            }
+        }
+        Command::Replay { disk, capture } => {
+            let trace = std::fs::read_to_string(&capture)
+                .context(format!("Could not read the capture at {capture:?}"))?;
+            let port = ReplayPort::from_trace(&trace)?;
+            let mut fdc_server = FdcServer::new(
+                &disk,
+                port,
+                fdcemu::baud_rate(fdcemu::DEFAULT_BAUD_RATE)?,
+                fdcemu::DEFAULT_TIMEOUT,
+            )?;
+
+            let mut commands = 0;
+            while !fdc_server.port().is_exhausted() {
+                commands += 1;
+                fdc_server
+                    .step()
+                    .context(format!("Command {commands} of the capture failed"))?;
+
+                if let Some(offset) = fdc_server.port().diverged_at() {
+                    bail!("Command {commands} answered differently than in the capture, from response byte {offset}");
+                }
+            }
+
+            let port = fdc_server.port();
+            if port.output().len() < port.expected().len() {
+                bail!(
+                    "The capture has {} more response bytes than the emulator sent",
+                    port.expected().len() - port.output().len()
+                );
+            }
+            info!("Replayed {commands} commands, all answered as in the capture");
+        }
+        Command::ListPorts => {
+            for port in ports::available_ports()? {
+                match port.usb {
+                    Some(usb) => println!(
+                        "{}\t{:04x}:{:04x}\t{}\t{}",
+                        port.path.display(),
+                        usb.vendor_id,
+                        usb.product_id,
+                        usb.manufacturer.as_deref().unwrap_or(""),
+                        usb.product.as_deref().unwrap_or(""),
+                    ),
+                    None => println!("{}", port.path.display()),
+                }
+            }
         }
         Command::Export {
             disk: disk_path,
             target,
+            yes,
+            gauge,
+            format,
+            needle_ruler,
+            chart_numbers,
+            center_offset,
+            patterns,
+            always_memo,
+            changed_since,
+            invert,
         } => {
-            let mut disk = Disk::new();
-            disk.load(&disk_path)
-                .context(format!("Could not read disk data from {disk_path:?}"))?;
-            let machine_state = MachineState::from_memory_dump(&disk.flatten_data());
+            let disk = load_disk(&disk_path)?;
+            let data = disk.memory();
+
+            // Patterns are parsed and written one at a time to keep memory use flat
+            let numbers = kh940::pattern_numbers(&data).collect::<Vec<_>>();
+            let missing = patterns
+                .iter()
+                .filter(|n| !numbers.contains(n))
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                bail!("No pattern numbered {} on the disk", missing.join(", "));
+            }
+
+            if target == Path::new(STDIO_PATH) {
+                let [number] = patterns[..] else {
+                    bail!("Exporting to standard output needs exactly one --pattern");
+                };
+                let machine_state = MachineState::from_memory_dump(&data)?;
+                let pattern = machine_state
+                    .pattern(number)
+                    .ok_or_else(|| eyre!("No pattern numbered {number} on the disk"))?;
+
+                let bytes = match format {
+                    PatternFormat::Png => encode_png(&pattern_image(
+                        pattern,
+                        gauge,
+                        needle_ruler,
+                        chart_numbers,
+                        center_offset,
+                        invert,
+                    ))?,
+                    PatternFormat::Ayab => encode_png(&interop::to_ayab_image(pattern))?,
+                };
+                std::io::stdout().lock().write_all(&bytes)?;
+                return Ok(());
+            }
+
             if !target.exists() {
                 std::fs::create_dir_all(&target)
                     .context(format!("Could not create target folder at {target:?}"))?;
+            } else if !is_empty_dir(&target)? {
+                confirm_overwrite(&format!("the files in {target:?}"), yes)?;
             }
 
-            for pattern in machine_state.patterns() {
-                let image = pattern.to_image();
-                image.save(target.join(format!("{}.png", pattern.pattern_number())))?;
+            // Keep the names and notes from an earlier export of the same folder
+            let mut manifest = Manifest::load(&target)?;
+            manifest.loaded_pattern = Some(kh940::loaded_pattern_number(&data));
+
+            for pattern in kh940::iter_patterns(&data) {
+                let pattern = pattern?;
+                if !patterns.is_empty() && !patterns.contains(&pattern.pattern_number()) {
+                    continue;
+                }
+
+                manifest
+                    .patterns
+                    .entry(pattern.pattern_number())
+                    .or_default();
+
+                if let Some(reference) = &changed_since {
+                    let reference_path =
+                        reference.join(format!("{}.png", pattern.pattern_number()));
+                    let unchanged = reference_path.exists()
+                        && read_pattern_image(
+                            pattern.pattern_number(),
+                            &reference_path,
+                            &ImageOptions {
+                                invert,
+                                ..ImageOptions::default()
+                            },
+                        )
+                        .is_ok_and(|r| r.rows() == pattern.rows());
+                    if unchanged {
+                        continue;
+                    }
+                }
+
+                let path = target.join(format!(
+                    "{}.{}",
+                    pattern.pattern_number(),
+                    format.extension()
+                ));
+
+                match format {
+                    PatternFormat::Png => pattern_image(
+                        &pattern,
+                        gauge,
+                        needle_ruler,
+                        chart_numbers,
+                        center_offset,
+                        invert,
+                    )
+                    .save(&path)?,
+                    PatternFormat::Ayab => interop::to_ayab_image(&pattern).save(&path)?,
+                }
+
+                let has_memo = pattern.memo().iter().any(|b| *b != 0);
+                if has_memo || always_memo {
+                    let memo_path = path.with_extension("memo");
+                    std::fs::write(&memo_path, pattern.memo())
+                        .context(format!("Could not write file at {memo_path:?}"))?;
+                }
             }
+
+            manifest.save(&target)?;
         }
         Command::Import {
             disk: disk_path,
             source,
+            yes,
+            keep_partial,
+            threshold,
+            threshold_inclusive,
+            quantize,
+            mirror,
+            dither,
+            invert,
+            next_number,
+            min_height,
+            canvas_width,
+            center,
+            start_number,
+            format,
+            snapshot,
+            keep_snapshots,
         } => {
-            let mut disk = Disk::new();
-            disk.load(&disk_path)
-                .context(format!("Could not read disk data from {disk_path:?}"))?;
-            let mut machine_state = MachineState::from_memory_dump(&disk.flatten_data());
+            let image_options = ImageOptions {
+                threshold: threshold
+                    .or(config.threshold)
+                    .unwrap_or(kh940::DEFAULT_THRESHOLD),
+                threshold_inclusive,
+                quantize_step: quantize,
+                mirror,
+                dither,
+                invert,
+            };
+
+            if !source.exists() {
+                bail!("Source folder {source:?} does not exist");
+            } else if !source.is_dir() {
+                bail!("Source {source:?} is not a directory");
+            }
+
+            // A disk image read from stdin is written to stdout, for pipelines
+            let to_stdout = disk_path == Path::new(STDIO_PATH);
+            if to_stdout && snapshot {
+                bail!("--snapshot needs a disk image file, not standard input");
+            }
+
+            let manifest = Manifest::load(&source)?;
+            let mut disk = load_disk(&disk_path)?;
+            let mut machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            let mut failures = vec![];
+            let mut imported = vec![];
+            let mut read = vec![];
+            let mut tiff_paths = vec![];
 
             for entry in source
                 .read_dir()
@@ -78,27 +811,1049 @@ fn main() -> Result<()> {
                 let entry = entry?;
 
                 let path = entry.path();
-                let pattern_number = path
+                let stem = path.file_stem().and_then(|f| f.to_str());
+                let pattern_number = stem.and_then(|f| f.parse::<u16>().ok());
+                let extension = path.extension().and_then(|f| f.to_str());
+
+                if matches!(extension, Some("tif" | "tiff")) {
+                    tiff_paths.push(path);
+                    continue;
+                }
+
+                // The KH-940 only stores the main bed's needle selection, see `Pattern`
+                if stem.is_some_and(is_second_bed_stem) && extension == Some(format.extension()) {
+                    failures.push(eyre!(
+                        "Could not read file at {path:?}: second bed needle selection is not \
+                        supported by the KH-940 memory format"
+                    ));
+                    continue;
+                }
+
+                if let Some(pattern_number) =
+                    pattern_number.filter(|_| extension == Some(format.extension()))
+                {
+                    read.push(read_pattern_file(
+                        pattern_number,
+                        &path,
+                        format,
+                        &image_options,
+                    ));
+                }
+            }
+
+            if !tiff_paths.is_empty() {
+                let mut pattern_number = start_number.ok_or_else(|| {
+                    eyre!(
+                        "Found TIFF files in {source:?}, pass --start-number to number their pages"
+                    )
+                })?;
+                tiff_paths.sort();
+
+                for path in &tiff_paths {
+                    let pages = match read_tiff_pages(path) {
+                        Ok(pages) => pages,
+                        Err(e) => {
+                            failures.push(e);
+                            continue;
+                        }
+                    };
+
+                    for (page, image) in pages.iter().enumerate() {
+                        read.push(
+                            Pattern::from_image(pattern_number, image, &image_options)
+                                .context(format!("Could not read page {} of {path:?}", page + 1)),
+                        );
+                        pattern_number += 1;
+                    }
+                }
+            }
+
+            for result in read {
+                match result {
+                    Ok(mut pattern) => {
+                        let pattern_number = pattern.pattern_number();
+                        if let Some(min_height) = min_height.filter(|&h| pattern.height() < h) {
+                            info!(
+                                "Padded pattern {pattern_number} from {} to {min_height} rows",
+                                pattern.height()
+                            );
+                            pattern.resize_canvas(pattern.width(), min_height);
+                        }
+                        if let Some(canvas_width) = canvas_width {
+                            let placed = pattern
+                                .place_on_canvas(canvas_width, center)
+                                .context(format!("Could not import pattern {pattern_number}"));
+                            if let Err(e) = placed {
+                                failures.push(e);
+                                continue;
+                            }
+                        }
+                        let added = machine_state
+                            .try_add_pattern(pattern)
+                            .context(format!("Could not import pattern {pattern_number}"));
+                        match added {
+                            Ok(()) => imported.push(pattern_number),
+                            Err(e) => failures.push(e),
+                        }
+                    }
+                    Err(e) => failures.push(e),
+                }
+            }
+
+            if !failures.is_empty() {
+                for failure in &failures {
+                    error!("{failure:#}");
+                }
+
+                if keep_partial {
+                    warn!(
+                        "Skipped {} file(s) that could not be imported",
+                        failures.len()
+                    );
+                } else {
+                    bail!(
+                        "{} file(s) could not be imported, {disk_path:?} was left untouched",
+                        failures.len()
+                    );
+                }
+            }
+
+            for number in manifest.patterns.keys() {
+                if !imported.contains(number) {
+                    warn!(
+                        "{} lists pattern {number}, which has no file",
+                        manifest::MANIFEST_FILE_NAME
+                    );
+                }
+            }
+
+            if let Some(loaded) = manifest.loaded_pattern {
+                if !machine_state.set_loaded_pattern(loaded) {
+                    warn!(
+                        "Pattern {loaded} was loaded when exported but no longer exists, \
+                        loading pattern {} instead",
+                        machine_state.loaded_pattern()
+                    );
+                }
+            }
+
+            if next_number.is_some() {
+                machine_state.set_next_pattern_number(next_number);
+            }
+
+            if to_stdout {
+                disk.set_flattened_data_exact(machine_state.serialize()?)?;
+                disk.save_to(std::io::stdout().lock())?;
+                return Ok(());
+            }
+
+            confirm_overwrite(&format!("the disk image at {disk_path:?}"), yes)?;
+            if snapshot {
+                snapshot_disk(&disk_path, usize::from(keep_snapshots))?;
+            }
+
+            save_machine_state(&mut disk, &disk_path, &mut machine_state, true)?;
+        }
+        Command::ImportOne {
+            disk: disk_path,
+            source,
+            number,
+            yes,
+            threshold,
+            threshold_inclusive,
+            quantize,
+            mirror,
+            dither,
+            invert,
+            format,
+        } => {
+            let image_options = ImageOptions {
+                threshold: threshold
+                    .or(config.threshold)
+                    .unwrap_or(kh940::DEFAULT_THRESHOLD),
+                threshold_inclusive,
+                quantize_step: quantize,
+                mirror,
+                dither,
+                invert,
+            };
+
+            let mut disk = load_writable_disk(&disk_path)?;
+            let mut machine_state = MachineState::from_memory_dump(&disk.memory())?;
+
+            let pattern = read_pattern_file(number, &source, format, &image_options)?;
+            if machine_state.pattern(number).is_some() {
+                info!("Replacing pattern {number}");
+            }
+            machine_state
+                .try_add_pattern(pattern)
+                .context(format!("Could not import {source:?}"))?;
+
+            save_machine_state(&mut disk, &disk_path, &mut machine_state, yes)?;
+        }
+        Command::Dedupe {
+            disk: disk_path,
+            dry_run,
+            include_memo,
+            yes,
+        } => {
+            let mut disk = load_writable_disk(&disk_path)?;
+            let mut machine_state = MachineState::from_memory_dump(&disk.memory())?;
+
+            let duplicates = find_duplicates(
+                machine_state.patterns(),
+                include_memo,
+                Pattern::content_checksum,
+            );
+
+            for numbers in &duplicates {
+                let numbers = numbers.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+                println!("{}", numbers.join(", "));
+            }
+
+            if dry_run || duplicates.is_empty() {
+                return Ok(());
+            }
+
+            // Patterns are sorted by number, so the first one in each group is kept
+            for numbers in &duplicates {
+                for number in &numbers[1..] {
+                    machine_state.remove_pattern(*number);
+                }
+            }
+
+            save_machine_state(&mut disk, &disk_path, &mut machine_state, yes)?;
+        }
+        Command::Info {
+            disk: disk_path,
+            layout,
+            control_data,
+        } => {
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+
+            println!("Patterns:            {}", machine_state.patterns().len());
+            println!("Loaded pattern:      {}", machine_state.loaded_pattern());
+            println!(
+                "Next pattern number: {}",
+                machine_state.next_pattern_number()
+            );
+            println!("Probably written by: {}", machine_state.origin_hint());
+
+            if layout {
+                println!("Layout:");
+                for (number, offset, size) in machine_state.layout_report() {
+                    println!("  {number}  {offset:#06x}  {size} bytes");
+                }
+                println!("  free    {} bytes", machine_state.free_pattern_memory());
+            }
+
+            if control_data {
+                println!("Control data:");
+                for (name, value) in machine_state.control_data_fields() {
+                    println!("  {name:<22}  {value:#010x}  {value}");
+                }
+                println!(
+                    "  free pattern memory     {} bytes",
+                    machine_state.free_pattern_memory()
+                );
+            }
+        }
+        Command::List { disk: disk_path } => {
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+
+            println!("Number  Width  Height  Bytes");
+            for pattern in machine_state.patterns() {
+                println!(
+                    "{:>6}  {:>5}  {:>6}  {:>5}",
+                    pattern.pattern_number(),
+                    pattern.width(),
+                    pattern.height(),
+                    pattern.memory_size()
+                );
+            }
+            println!("Loaded pattern: {}", machine_state.loaded_pattern());
+        }
+        Command::Status {
+            disk: disk_path,
+            source,
+            threshold,
+            threshold_inclusive,
+        } => {
+            let image_options = ImageOptions {
+                threshold: threshold
+                    .or(config.threshold)
+                    .unwrap_or(kh940::DEFAULT_THRESHOLD),
+                threshold_inclusive,
+                ..ImageOptions::default()
+            };
+
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+
+            let mut statuses = BTreeMap::new();
+            for entry in source
+                .read_dir()
+                .context(format!("Could not read source folder at {source:?}"))?
+            {
+                let path = entry?.path();
+                if path.extension().and_then(|f| f.to_str()) != Some("png") {
+                    continue;
+                }
+                let Some(pattern_number) = path
                     .file_stem()
                     .and_then(|f| f.to_str())
-                    .and_then(|f| f.parse::<u16>().ok());
-                let extension = path.extension().and_then(|f| f.to_str());
-                if let (Some(pattern_number), Some("png")) = (pattern_number, extension) {
-                    let image =
-                        image::open(&path).context(format!("Could not read file at {path:?}"))?;
-                    let grayscale = image::imageops::grayscale(&image);
+                    .and_then(|f| f.parse::<u16>().ok())
+                else {
+                    continue;
+                };
 
-                    let pattern = Pattern::from_image(pattern_number, &grayscale)
-                        .context(format!("Could not read file at {path:?}"))?;
-                    machine_state.add_pattern(pattern);
+                let pattern = read_pattern_image(pattern_number, &path, &image_options)?;
+                let status = match machine_state.pattern(pattern_number) {
+                    Some(existing) if existing.equals_ignoring_memo(&pattern) => "unchanged",
+                    Some(_) => "modified",
+                    None => "new",
+                };
+                statuses.insert(pattern_number, status);
+            }
+
+            for pattern in machine_state.patterns() {
+                statuses
+                    .entry(pattern.pattern_number())
+                    .or_insert("disk-only");
+            }
+
+            for (number, status) in statuses {
+                println!("{status:<10} {number}");
+            }
+        }
+        Command::Browse { disk: disk_path } => {
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            let patterns = machine_state.patterns();
+            if patterns.is_empty() {
+                println!("No patterns on {disk_path:?}");
+                return Ok(());
+            }
+
+            let mut index = 0;
+            loop {
+                let pattern = &patterns[index];
+                println!(
+                    "\nPattern {} ({}x{}), {} of {}",
+                    pattern.pattern_number(),
+                    pattern.width(),
+                    pattern.height(),
+                    index + 1,
+                    patterns.len()
+                );
+                print!("{}", pattern.to_ascii());
+                print!("[n]ext, [p]revious or [q]uit? ");
+                std::io::stdout().flush()?;
+
+                let mut answer = String::new();
+                if std::io::stdin().read_line(&mut answer)? == 0 {
+                    break;
                 }
+
+                match answer.trim() {
+                    "" | "n" => index = (index + 1) % patterns.len(),
+                    "p" => index = (index + patterns.len() - 1) % patterns.len(),
+                    "q" => break,
+                    other => println!("Unknown choice {other:?}"),
+                }
+            }
+        }
+        #[cfg(feature = "gui")]
+        Command::View { disk: disk_path } => {
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            if machine_state.patterns().is_empty() {
+                println!("No patterns on {disk_path:?}");
+                return Ok(());
             }
 
-            let data = machine_state.serialize();
-            disk.set_flattened_data(data)?;
+            view::show_patterns(machine_state.patterns())?;
+        }
+        Command::Show {
+            disk: disk_path,
+            pattern,
+            debug,
+        } => {
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            let pattern = machine_state
+                .pattern(pattern)
+                .ok_or_else(|| eyre!("No pattern numbered {pattern} on the disk"))?;
+
+            if debug {
+                let layout = pattern.serialized_layout();
+                println!(
+                    "Row nibbles: {}, row padding: {} bits, initial padding: {} nibbles, \
+                    stitch data: {} bytes, memo: {} bytes",
+                    layout.row_nibbles,
+                    layout.row_pad_bits,
+                    layout.initial_padding,
+                    layout.stitch_data_len,
+                    layout.memo_len
+                );
+                print!("{}", pattern.debug_rows());
+            } else {
+                print!("{}", pattern.to_ascii());
+            }
+        }
+        Command::Screenshot {
+            disk: disk_path,
+            pattern,
+            output,
+        } => {
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            let pattern = machine_state
+                .pattern(pattern)
+                .ok_or_else(|| eyre!("No pattern numbered {pattern} on the disk"))?;
+
+            render::lcd_screenshot(&pattern.to_image())
+                .save(&output)
+                .context(format!("Could not write screenshot to {output:?}"))?;
+        }
+        Command::Verify { disk: disk_path } => {
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            let inconsistent = machine_state.inconsistent_patterns();
+
+            println!("Inconsistent patterns: {}", inconsistent.len());
+            for number in inconsistent {
+                println!("  {number}");
+            }
+
+            if !inconsistent.is_empty() {
+                bail!("{disk_path:?} has patterns that overlap or leave gaps in memory");
+            }
+        }
+        Command::Lint { source, format } => {
+            let mut seen = BTreeMap::new();
+            let mut problems = 0;
+            let mut report = |path: &Path, problem: String| {
+                println!("{}: {problem}", path.display());
+                problems += 1;
+            };
+
+            let mut paths = source
+                .read_dir()
+                .context(format!("Could not read source folder at {source:?}"))?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            paths.sort();
+
+            for path in paths {
+                if path.extension().and_then(|f| f.to_str()) != Some(format.extension()) {
+                    continue;
+                }
+
+                let stem = path.file_stem().and_then(|f| f.to_str()).unwrap_or("");
+                let Ok(pattern_number) = stem.parse::<u16>() else {
+                    if is_second_bed_stem(stem) {
+                        report(&path, "second bed patterns are not supported".to_owned());
+                    } else {
+                        report(&path, "file name is not a pattern number".to_owned());
+                    }
+                    continue;
+                };
+
+                if !kh940::PATTERN_NUMBERS.contains(&pattern_number) {
+                    report(
+                        &path,
+                        format!(
+                            "pattern number {pattern_number} is outside of {}..={}",
+                            kh940::PATTERN_NUMBERS.start(),
+                            kh940::PATTERN_NUMBERS.end()
+                        ),
+                    );
+                }
+
+                if let Some(first) = seen.insert(pattern_number, path.clone()) {
+                    report(
+                        &path,
+                        format!(
+                            "pattern number {pattern_number} is already used by {}",
+                            first.display()
+                        ),
+                    );
+                }
+
+                // Check the size first, since reading a pattern that's too large
+                // only reports the first dimension over the limit
+                if let Ok((width, height)) = image::image_dimensions(&path) {
+                    let too_wide = width > u32::from(kh940::MAX_WIDTH);
+                    let too_high = height > u32::from(kh940::MAX_HEIGHT);
+                    if too_wide {
+                        report(
+                            &path,
+                            format!(
+                                "{width} stitches wide, the machine knits at most {}",
+                                kh940::MAX_WIDTH
+                            ),
+                        );
+                    }
+                    if too_high {
+                        report(
+                            &path,
+                            format!(
+                                "{height} rows high, patterns can be at most {}",
+                                kh940::MAX_HEIGHT
+                            ),
+                        );
+                    }
+                    if too_wide || too_high {
+                        continue;
+                    }
+                }
+
+                if let Err(e) =
+                    read_pattern_file(pattern_number, &path, format, &ImageOptions::default())
+                {
+                    report(&path, format!("{:#}", e.root_cause()));
+                }
+            }
+
+            if problems > 0 {
+                bail!("Found {problems} problem(s) in {source:?}");
+            }
+        }
+        Command::Create {
+            disk: disk_path,
+            yes,
+        } => {
+            let mut disk = Disk::new();
+            disk.initialize_sector_ids();
+            let mut machine_state = MachineState::empty();
+
+            save_machine_state(
+                &mut disk,
+                &disk_path,
+                &mut machine_state,
+                yes || !disk_path.exists(),
+            )?;
+        }
+        Command::GenerateSamples {
+            disk: disk_path,
+            count,
+            yes,
+        } => {
+            let mut disk = Disk::new();
+            disk.initialize_sector_ids();
+            let mut machine_state = MachineState::empty();
+
+            let patterns = samples::sample_patterns(usize::from(count))?;
+            if patterns.len() < usize::from(count) {
+                warn!(
+                    "Only {} of {count} patterns fit in the machine's memory",
+                    patterns.len()
+                );
+            }
+            for pattern in patterns {
+                machine_state.add_pattern(pattern);
+            }
+
+            save_machine_state(
+                &mut disk,
+                &disk_path,
+                &mut machine_state,
+                yes || !disk_path.exists(),
+            )?;
+        }
+        Command::ExtractIds {
+            disk: disk_path,
+            out,
+        } => {
+            let disk = load_disk(&disk_path)?;
+            std::fs::write(&out, disk.flatten_ids())
+                .context(format!("Could not write sector IDs to {out:?}"))?;
+        }
+        Command::ApplyIds {
+            disk: disk_path,
+            ids,
+            yes,
+        } => {
+            let mut disk = load_writable_disk(&disk_path)?;
+            let data =
+                std::fs::read(&ids).context(format!("Could not read sector IDs from {ids:?}"))?;
+            disk.set_flattened_ids(&data)
+                .context(format!("Could not apply sector IDs from {ids:?}"))?;
+
+            confirm_overwrite(&format!("the disk image at {disk_path:?}"), yes)?;
             disk.save(&disk_path)?;
         }
+        Command::Repair {
+            disk: disk_path,
+            yes,
+        } => {
+            let mut disk = load_writable_disk(&disk_path)?;
+            let mut machine_state = MachineState::from_memory_dump(&disk.memory())?;
+
+            if !machine_state.rebuild_control_data()? {
+                info!("The control data of {disk_path:?} is already consistent");
+                return Ok(());
+            }
+
+            info!("Rebuilt the control data of {disk_path:?}");
+            save_machine_state(&mut disk, &disk_path, &mut machine_state, yes)?;
+        }
+        Command::Delete {
+            disk: disk_path,
+            pattern,
+            yes,
+        } => {
+            let mut disk = load_writable_disk(&disk_path)?;
+            let mut machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            if !machine_state.remove_pattern(pattern) {
+                bail!("No pattern numbered {pattern} on the disk");
+            }
+
+            save_machine_state(&mut disk, &disk_path, &mut machine_state, yes)?;
+            info!("Deleted pattern {pattern}");
+        }
+        Command::Renumber {
+            disk: disk_path,
+            from,
+            to,
+            yes,
+        } => {
+            let mut disk = load_writable_disk(&disk_path)?;
+            let mut machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            machine_state
+                .renumber_pattern(from, to)
+                .context(format!("Could not renumber pattern {from} to {to}"))?;
+
+            save_machine_state(&mut disk, &disk_path, &mut machine_state, yes)?;
+        }
+        Command::Clear {
+            disk: disk_path,
+            yes,
+        } => {
+            let mut disk = load_writable_disk(&disk_path)?;
+            let mut machine_state = MachineState::from_memory_dump(&disk.memory())?;
+            machine_state.clear_patterns();
+
+            save_machine_state(&mut disk, &disk_path, &mut machine_state, yes)?;
+        }
+        Command::Find {
+            disk: disk_path,
+            width,
+            height,
+            matches,
+        } => {
+            let disk = load_disk(&disk_path)?;
+            let machine_state = MachineState::from_memory_dump(&disk.memory())?;
+
+            let reference = matches
+                .map(|path| read_pattern_image(0, &path, &ImageOptions::default()))
+                .transpose()?;
+
+            for pattern in machine_state.patterns() {
+                if width.is_some_and(|w| w != pattern.width())
+                    || height.is_some_and(|h| h != pattern.height())
+                    || reference
+                        .as_ref()
+                        .is_some_and(|r| r.rows() != pattern.rows())
+                {
+                    continue;
+                }
+
+                println!("{}", pattern.pattern_number());
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Configure the emulator from the `emulate` options and serve the machine
+fn serve<D: DiskStorage>(
+    mut fdc_server: FdcServer<TracedPort<SystemPort>, D>,
+    session_log: Option<PathBuf>,
+    response_delay_ms: u64,
+    progress: bool,
+    init_only: bool,
+    ephemeral: bool,
+) -> Result<()> {
+    if let Some(session_log) = session_log {
+        fdc_server
+            .set_session_log(&session_log)
+            .context(format!("Could not open session log at {session_log:?}"))?;
+    }
+
+    fdc_server.set_response_delay(Duration::from_millis(response_delay_ms));
+    fdc_server.set_ephemeral(ephemeral);
+    if progress {
+        fdc_server.on_event(log_progress);
+    }
+
+    if init_only {
+        fdc_server.run_handshake()?;
+        info!("The machine connected and switched to FDC mode");
+    } else {
+        let stop = fdc_server.stop_handle();
+        ctrlc::set_handler(move || {
+            if stop.has_unsaved_changes() && !stop.is_stop_requested() {
+                info!("Stopping once the disk is saved, press Ctrl-C again to quit right away");
+                stop.request_stop();
+            } else {
+                std::process::exit(130);
+            }
+        })
+        .context("Could not install the Ctrl-C handler")?;
+
+        fdc_server.run()?;
+        info!("Disk saved, stopped");
+    }
+
+    Ok(())
+}
+
+/// Summarize a disk being served by the emulator, without parsing its patterns
+fn log_disk_status(disk: &Disk) {
+    let data = disk.memory();
+    info!(
+        "Disk has {} pattern(s)",
+        kh940::pattern_numbers(&data).count()
+    );
+}
+
+fn log_progress(event: FdcEvent) {
+    match event {
+        FdcEvent::ModeSwitched {
+            to: FdcMode::Fdc, ..
+        } => info!("Machine connected"),
+        FdcEvent::ModeSwitched {
+            to: FdcMode::Op, ..
+        } => info!("Machine done"),
+        FdcEvent::SectorsRead { psn, count } => {
+            info!("Machine reading {count} sector(s) from {psn}")
+        }
+        FdcEvent::SectorsWritten { psn, count } => {
+            info!("Machine writing {count} sector(s) at {psn}")
+        }
+        FdcEvent::IdRead { psn } => info!("Machine reading the ID of sector {psn}"),
+        FdcEvent::IdWritten { psn } => info!("Machine writing the ID of sector {psn}"),
+        FdcEvent::IdSearched { psn: Some(psn) } => info!("Machine found sector {psn} by ID"),
+        FdcEvent::IdSearched { psn: None } => info!("Machine searched for a missing sector ID"),
+        FdcEvent::Formatted => info!("Machine formatted the disk"),
+    }
+}
+
+impl PatternFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            PatternFormat::Png | PatternFormat::Ayab => "png",
+        }
+    }
+}
+
+/// Whether a file name stem looks like `<number>_b`, a ribber plane for a pattern
+fn is_second_bed_stem(stem: &str) -> bool {
+    stem.strip_suffix("_b")
+        .is_some_and(|n| n.parse::<u16>().is_ok())
+}
+
+/// Path argument that means standard input or output instead of a file
+const STDIO_PATH: &str = "-";
+
+fn load_disk(path: &Path) -> Result<Disk> {
+    let mut disk = Disk::new();
+    if path == Path::new(STDIO_PATH) {
+        disk.load_from_reader(std::io::stdin().lock())
+            .context("Could not read disk data from standard input")?;
+    } else {
+        disk.load(path)
+            .context(format!("Could not read disk data from {path:?}"))?;
+    }
+
+    Ok(disk)
+}
+
+/// Load a disk image that will be written back, which rules out standard input
+fn load_writable_disk(path: &Path) -> Result<Disk> {
+    check_writable(path)?;
+    load_disk(path)
+}
+
+fn check_writable(path: &Path) -> Result<()> {
+    if path == Path::new(STDIO_PATH) {
+        bail!("This command writes the disk image back, so it can't be read from standard input");
+    }
+
+    Ok(())
+}
+
+/// Draw a pattern as a PNG chart the way `export` does
+fn pattern_image(
+    pattern: &Pattern,
+    gauge: Gauge,
+    needle_ruler: bool,
+    chart_numbers: Option<ChartNumbering>,
+    center_offset: i32,
+    invert: bool,
+) -> GrayImage {
+    let mut image = pattern.to_image();
+    if invert {
+        image::imageops::invert(&mut image);
+    }
+    image = render::apply_gauge(&image, gauge);
+    if needle_ruler {
+        image = render::add_needle_ruler(&image, gauge, center_offset);
+    }
+    if let Some(numbering) = chart_numbers {
+        image = render::add_chart_numbers(&image, gauge, numbering);
+    }
+
+    image
+}
+
+fn encode_png(image: &GrayImage) -> Result<Vec<u8>> {
+    let mut png = Cursor::new(vec![]);
+    image.write_to(&mut png, ImageOutputFormat::Png)?;
+
+    Ok(png.into_inner())
+}
+
+fn read_pattern_image(pattern_number: u16, path: &Path, options: &ImageOptions) -> Result<Pattern> {
+    let image = image::open(path).context(format!("Could not read file at {path:?}"))?;
+    let grayscale = image::imageops::grayscale(&image);
+
+    Pattern::from_image(pattern_number, &grayscale, options)
+        .context(format!("Could not read file at {path:?}"))
+}
+
+/// Serialize the machine state into the disk and save it, after confirming the overwrite
+fn save_machine_state(
+    disk: &mut Disk,
+    disk_path: &Path,
+    machine_state: &mut MachineState,
+    yes: bool,
+) -> Result<()> {
+    check_writable(disk_path)?;
+    confirm_overwrite(&format!("the disk image at {disk_path:?}"), yes)?;
+
+    let data = machine_state.serialize()?;
+    disk.set_flattened_data_exact(data)?;
+    disk.save(disk_path)?;
+
+    Ok(())
+}
+
+/// Copy the disk image to a timestamped `.bak` file next to it, keeping only the newest `keep`
+fn snapshot_disk(disk_path: &Path, keep: usize) -> Result<()> {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let file_name = disk_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or_else(|| eyre!("Disk image path {disk_path:?} has no file name"))?;
+    let snapshot_path = disk_path.with_file_name(format!("{file_name}.{timestamp_ms}.bak"));
+
+    std::fs::copy(disk_path, &snapshot_path)
+        .context(format!("Could not write snapshot to {snapshot_path:?}"))?;
+    info!("Saved a snapshot of {disk_path:?} to {snapshot_path:?}");
+
+    let folder = match disk_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut snapshots = vec![];
+    for entry in folder
+        .read_dir()
+        .context(format!("Could not read folder at {folder:?}"))?
+    {
+        let path = entry?.path();
+        let timestamp = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .and_then(|f| {
+                f.strip_prefix(file_name)?
+                    .strip_prefix('.')?
+                    .strip_suffix(".bak")
+            })
+            .and_then(|t| t.parse::<u128>().ok());
+        if let Some(timestamp) = timestamp {
+            snapshots.push((timestamp, path));
+        }
+    }
+
+    snapshots.sort();
+    let prune = snapshots.len().saturating_sub(keep);
+    for (_, path) in &snapshots[..prune] {
+        std::fs::remove_file(path).context(format!("Could not delete old snapshot {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Decode every page of a TIFF file into a grayscale image
+fn read_tiff_pages(path: &Path) -> Result<Vec<GrayImage>> {
+    use tiff::{decoder::DecodingResult, ColorType};
+
+    let file = std::fs::File::open(path).context(format!("Could not read file at {path:?}"))?;
+    let mut decoder = tiff::decoder::Decoder::new(std::io::BufReader::new(file))
+        .context(format!("Could not read TIFF file at {path:?}"))?;
+    let mut pages = vec![];
+
+    loop {
+        let page = pages.len() + 1;
+        let (width, height) = decoder.dimensions()?;
+        let color_type = decoder.colortype()?;
+        let DecodingResult::U8(data) = decoder
+            .read_image()
+            .context(format!("Could not decode page {page} of {path:?}"))?
+        else {
+            bail!("Page {page} of {path:?} is not an 8 bit image");
+        };
+
+        let image = match color_type {
+            ColorType::Gray(8) => GrayImage::from_raw(width, height, data).map(DynamicImage::from),
+            ColorType::GrayA(8) => {
+                image::GrayAlphaImage::from_raw(width, height, data).map(DynamicImage::from)
+            }
+            ColorType::RGB(8) => {
+                image::RgbImage::from_raw(width, height, data).map(DynamicImage::from)
+            }
+            ColorType::RGBA(8) => {
+                image::RgbaImage::from_raw(width, height, data).map(DynamicImage::from)
+            }
+            other => bail!("Page {page} of {path:?} has unsupported color type {other:?}"),
+        }
+        .ok_or_else(|| eyre!("Page {page} of {path:?} has the wrong amount of pixel data"))?;
+
+        pages.push(image::imageops::grayscale(&image));
+
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    Ok(pages)
+}
+
+fn read_pattern_file(
+    pattern_number: u16,
+    path: &Path,
+    format: PatternFormat,
+    options: &ImageOptions,
+) -> Result<Pattern> {
+    let mut pattern = match format {
+        PatternFormat::Png => read_pattern_image(pattern_number, path, options)?,
+        PatternFormat::Ayab => {
+            let image = image::open(path).context(format!("Could not read file at {path:?}"))?;
+            interop::from_ayab_image(pattern_number, &image.to_luma8(), options)
+                .context(format!("Could not read file at {path:?}"))?
+        }
+    };
+
+    // Images get their memo from the `<n>.memo` file export writes next to them
+    let memo_path = path.with_extension("memo");
+    if memo_path.exists() {
+        let memo =
+            std::fs::read(&memo_path).context(format!("Could not read file at {memo_path:?}"))?;
+        pattern
+            .set_memo(memo)
+            .context(format!("Could not read file at {memo_path:?}"))?;
+    }
+
+    Ok(pattern)
+}
+
+/// Group the numbers of patterns with the same stitches, and memo if `include_memo` is set
+///
+/// Patterns are bucketed by `key` first, then compared stitch by stitch, so a
+/// collision in `key` never makes two different patterns duplicates. Only
+/// groups with more than one pattern are returned, each in the order of
+/// `patterns`.
+fn find_duplicates<K: Ord>(
+    patterns: &[Pattern],
+    include_memo: bool,
+    key: impl Fn(&Pattern) -> K,
+) -> Vec<Vec<u16>> {
+    let same = |a: &Pattern, b: &Pattern| {
+        a.width() == b.width()
+            && a.height() == b.height()
+            && a.rows() == b.rows()
+            && (!include_memo || a.memo() == b.memo())
+    };
+
+    let mut buckets: BTreeMap<_, Vec<Vec<&Pattern>>> = BTreeMap::new();
+    for pattern in patterns {
+        let groups = buckets.entry(key(pattern)).or_default();
+        match groups.iter_mut().find(|group| same(group[0], pattern)) {
+            Some(group) => group.push(pattern),
+            None => groups.push(vec![pattern]),
+        }
+    }
+
+    let mut duplicates = buckets
+        .into_values()
+        .flatten()
+        .filter(|group| group.len() > 1)
+        .map(|group| group.iter().map(|p| p.pattern_number()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    duplicates.sort_unstable();
+    duplicates
+}
+
+fn is_empty_dir(path: &Path) -> Result<bool> {
+    let mut entries = path
+        .read_dir()
+        .context(format!("Could not read folder at {path:?}"))?;
+
+    Ok(entries.next().is_none())
+}
+
+/// Ask the user to confirm overwriting `what`, unless `yes` was given
+///
+/// When stdin isn't a terminal there's nobody to ask, so `--yes` is required.
+fn confirm_overwrite(what: &str, yes: bool) -> Result<()> {
+    if yes {
+        return Ok(());
+    }
+
+    if !std::io::stdin().is_terminal() {
+        bail!("This would overwrite {what}; pass --yes to proceed non-interactively");
+    }
+
+    print!("This will overwrite {what}. Continue? [y/N] ");
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        bail!("Aborted, nothing was written");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_find_duplicates() {
+    let stripes = |number, selected: bool| {
+        let rows = vec![vec![selected, !selected]; 2];
+        Pattern::from_bitmap(number, rows).unwrap()
+    };
+    let mut patterns = vec![stripes(901, true), stripes(902, false), stripes(903, true)];
+
+    let duplicates = find_duplicates(&patterns, false, Pattern::content_checksum);
+    assert_eq!(duplicates, [[901, 903]]);
+
+    // A key that collides for every pattern still only groups equal stitches
+    let duplicates = find_duplicates(&patterns, false, |_| 0);
+    assert_eq!(duplicates, [[901, 903]]);
+
+    let memo = vec![1; patterns[2].memo().len()];
+    patterns[2].set_memo(memo).unwrap();
+    assert_eq!(find_duplicates(&patterns, false, |_| 0), [[901, 903]]);
+    assert!(find_duplicates(&patterns, true, |_| 0).is_empty());
+}
@@ -0,0 +1,88 @@
+use std::{collections::BTreeMap, path::Path};
+
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// File name of the manifest in an import/export folder
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Names and notes for the patterns in an import/export folder
+///
+/// The machine has nowhere to store this, so it only lives next to the
+/// pattern files for the operator's benefit and never touches the disk image.
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// The pattern the machine had loaded when exported, restored on import
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loaded_pattern: Option<u16>,
+
+    #[serde(default)]
+    pub patterns: BTreeMap<u16, PatternMetadata>,
+}
+
+#[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatternMetadata {
+    #[serde(default)]
+    pub name: String,
+
+    #[serde(default)]
+    pub notes: String,
+
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Manifest {
+    /// Read the manifest in `folder`, or an empty one if there is none
+    pub fn load(folder: &Path) -> Result<Manifest> {
+        let path = folder.join(MANIFEST_FILE_NAME);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .context(format!("Could not read manifest at {path:?}"))?;
+
+        serde_json::from_str(&contents).context(format!("Could not parse manifest at {path:?}"))
+    }
+
+    pub fn save(&self, folder: &Path) -> Result<()> {
+        let path = folder.join(MANIFEST_FILE_NAME);
+        let mut contents = serde_json::to_string_pretty(self)?;
+        contents.push('\n');
+
+        std::fs::write(&path, contents).context(format!("Could not write manifest at {path:?}"))
+    }
+}
+
+#[test]
+fn test_parse_manifest() {
+    let manifest: Manifest = serde_json::from_str(
+        r#"{
+            "patterns": {
+                "901": { "name": "heart motif v2", "tags": ["hearts"] },
+                "902": {}
+            }
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(manifest.patterns[&901].name, "heart motif v2");
+    assert_eq!(manifest.patterns[&901].notes, "");
+    assert_eq!(manifest.patterns[&901].tags, vec!["hearts"]);
+    assert_eq!(manifest.patterns[&902], PatternMetadata::default());
+    assert_eq!(manifest.loaded_pattern, None);
+
+    let round_trip = serde_json::to_string(&manifest).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Manifest>(&round_trip).unwrap(),
+        manifest
+    );
+
+    assert!(serde_json::from_str::<Manifest>(r#"{"unknown": 1}"#).is_err());
+
+    let manifest: Manifest = serde_json::from_str(r#"{"loaded_pattern": 905}"#).unwrap();
+    assert_eq!(manifest.loaded_pattern, Some(905));
+}
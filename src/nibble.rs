@@ -41,4 +41,4 @@ fn combine_nibbles() {
 
     let byte = Nibble::combine_nibbles(n1, n2);
     assert_eq!(byte, 0x3d);
-}
\ No newline at end of file
+}
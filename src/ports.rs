@@ -0,0 +1,91 @@
+use std::path::{Path, PathBuf};
+
+use knitty2::Result;
+
+/// Device name prefixes in `/dev` that are likely to be USB serial adapters
+#[cfg(target_os = "macos")]
+const DEVICE_PREFIXES: &[&str] = &[
+    "tty.usbserial",
+    "tty.usbmodem",
+    "cu.usbserial",
+    "cu.usbmodem",
+];
+#[cfg(all(unix, not(target_os = "macos")))]
+const DEVICE_PREFIXES: &[&str] = &["ttyUSB", "ttyACM"];
+
+pub struct PortInfo {
+    pub path: PathBuf,
+    pub usb: Option<UsbInfo>,
+}
+
+pub struct UsbInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+/// List serial devices that look like USB serial adapters, without opening them
+#[cfg(unix)]
+pub fn available_ports() -> Result<Vec<PortInfo>> {
+    let mut ports = vec![];
+
+    for entry in Path::new("/dev").read_dir()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+
+        if DEVICE_PREFIXES.iter().any(|p| name.starts_with(p)) {
+            ports.push(PortInfo {
+                path: entry.path(),
+                usb: usb_info(name),
+            });
+        }
+    }
+
+    ports.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(ports)
+}
+
+#[cfg(not(unix))]
+pub fn available_ports() -> Result<Vec<PortInfo>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Listing serial ports is not supported on this platform",
+    )
+    .into())
+}
+
+/// Look up the USB device a tty belongs to through sysfs
+///
+/// Only Linux exposes this information as plain files, so other platforms
+/// always get `None`.
+#[cfg(unix)]
+fn usb_info(device_name: &str) -> Option<UsbInfo> {
+    let device = Path::new("/sys/class/tty")
+        .join(device_name)
+        .join("device")
+        .canonicalize()
+        .ok()?;
+
+    let usb_device = device
+        .ancestors()
+        .find(|dir| dir.join("idVendor").exists())?;
+
+    let read = |file: &str| {
+        std::fs::read_to_string(usb_device.join(file))
+            .ok()
+            .map(|s| s.trim().to_owned())
+    };
+    let read_id = |file: &str| u16::from_str_radix(&read(file)?, 16).ok();
+
+    Some(UsbInfo {
+        vendor_id: read_id("idVendor")?,
+        product_id: read_id("idProduct")?,
+        manufacturer: read("manufacturer"),
+        product: read("product"),
+    })
+}
@@ -0,0 +1,473 @@
+use std::{fmt, str::FromStr};
+
+use eyre::{ensure, eyre, Context, Report};
+use image::{imageops, GrayImage};
+
+/// The size in pixels each stitch is rendered as, written as `<w>:<h>`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Gauge {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Gauge {
+    pub const SQUARE: Gauge = Gauge {
+        width: 1,
+        height: 1,
+    };
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Gauge::SQUARE
+    }
+}
+
+impl FromStr for Gauge {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("Expected a gauge like 4:3, got {s:?}"))?;
+
+        let width = width
+            .trim()
+            .parse::<u32>()
+            .context(format!("Invalid gauge width {width:?}"))?;
+        let height = height
+            .trim()
+            .parse::<u32>()
+            .context(format!("Invalid gauge height {height:?}"))?;
+
+        ensure!(width > 0 && height > 0, "Gauge values must be at least 1");
+
+        Ok(Gauge { width, height })
+    }
+}
+
+impl fmt::Display for Gauge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.width, self.height)
+    }
+}
+
+/// Scale a one-pixel-per-stitch image so each stitch becomes a `gauge` sized block
+pub fn apply_gauge(image: &GrayImage, gauge: Gauge) -> GrayImage {
+    if gauge == Gauge::SQUARE {
+        return image.clone();
+    }
+
+    imageops::resize(
+        image,
+        image.width() * gauge.width,
+        image.height() * gauge.height,
+        imageops::FilterType::Nearest,
+    )
+}
+
+/// Height of the needle ruler drawn above a chart, in pixels
+const RULER_HEIGHT: u32 = 12;
+
+/// 3x5 pixel glyphs for the characters used in labels, one row per byte
+pub const GLYPH_WIDTH: u32 = 3;
+pub const GLYPH_HEIGHT: u32 = 5;
+const GLYPHS: &[(char, [u8; 5])] = &[
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+];
+
+/// A needle on the machine bed, numbered outwards from the center
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Needle {
+    Left(u32),
+    Right(u32),
+}
+
+impl fmt::Display for Needle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Needle::Left(n) => write!(f, "L{n}"),
+            Needle::Right(n) => write!(f, "R{n}"),
+        }
+    }
+}
+
+impl Needle {
+    /// The needle a pattern column is knitted on
+    ///
+    /// The pattern is centered on the bed, where the needles are numbered L1
+    /// and R1 on either side of the center mark, and then moved `center_offset`
+    /// needles to the right.
+    pub fn for_column(column: u32, pattern_width: u32, center_offset: i32) -> Needle {
+        let position = i64::from(column) - i64::from(pattern_width / 2) + i64::from(center_offset);
+
+        if position < 0 {
+            Needle::Left(position.unsigned_abs() as u32)
+        } else {
+            Needle::Right(position as u32 + 1)
+        }
+    }
+
+    fn number(self) -> u32 {
+        match self {
+            Needle::Left(n) | Needle::Right(n) => n,
+        }
+    }
+}
+
+/// Add a ruler above a chart showing which needle each stitch column is knitted on
+///
+/// Every needle gets a tick, and needles 1, 10, 20, ... on both sides get a
+/// longer tick. Labels are drawn as densely as the gauge allows, skipping
+/// any that would overlap the previous one.
+pub fn add_needle_ruler(chart: &GrayImage, gauge: Gauge, center_offset: i32) -> GrayImage {
+    let columns = chart.width() / gauge.width;
+    let mut image =
+        GrayImage::from_pixel(chart.width(), chart.height() + RULER_HEIGHT, [255].into());
+    imageops::replace(&mut image, chart, 0, i64::from(RULER_HEIGHT));
+
+    // "L100" is the widest label
+    let label_width = 4 * (GLYPH_WIDTH + 1);
+    let label_every = (label_width + 2).div_ceil(10 * gauge.width) * 10;
+    let mut next_label_x = 0;
+
+    for column in 0..columns {
+        let needle = Needle::for_column(column, columns, center_offset);
+        let center_x = column * gauge.width + gauge.width / 2;
+        let major = needle.number() == 1 || needle.number().is_multiple_of(10);
+
+        let tick_height = if major { 4 } else { 2 };
+        for y in RULER_HEIGHT - tick_height..RULER_HEIGHT {
+            image.put_pixel(center_x, y, [0].into());
+        }
+
+        if needle.number() == 1 || needle.number().is_multiple_of(label_every) {
+            let text = needle.to_string();
+            let text_width = text.len() as u32 * (GLYPH_WIDTH + 1) - 1;
+            let x = center_x
+                .saturating_sub(text_width / 2)
+                .min(image.width().saturating_sub(text_width));
+
+            if x >= next_label_x {
+                draw_text(&mut image, &text, x.into(), 1);
+                next_label_x = x + text_width + 2;
+            }
+        }
+    }
+
+    image
+}
+
+/// Which way rows and columns are counted on a numbered chart
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChartNumbering {
+    /// Columns from the right and rows from the bottom, like hand-knitting charts
+    Conventional,
+    /// Columns from the left and rows from the top, like reading text
+    Reading,
+}
+
+/// Height of the column numbers above a numbered chart, in pixels
+const COLUMN_NUMBERS_HEIGHT: u32 = 7;
+
+/// Width of the row numbers next to a numbered chart, fitting three digits
+const ROW_NUMBERS_WIDTH: u32 = 3 * (GLYPH_WIDTH + 1) + 2;
+
+/// Space a column or row label takes up, at most three digits
+const COLUMN_LABEL_WIDTH: u32 = 3 * (GLYPH_WIDTH + 1);
+const ROW_LABEL_HEIGHT: u32 = 5;
+
+/// The smallest "round" interval between labels that keeps them `label_size` pixels apart
+fn label_interval(label_size: u32, cell_size: u32) -> u32 {
+    [1, 2, 5, 10, 20, 50, 100]
+        .into_iter()
+        .find(|interval| interval * cell_size > label_size)
+        .unwrap_or(100)
+}
+
+/// Whether to label `number`: always the first, then every `interval`th that
+/// doesn't crowd the first
+fn is_labelled(number: u32, interval: u32, cell_size: u32, label_size: u32) -> bool {
+    number == 1 || (number.is_multiple_of(interval) && (number - 1) * cell_size > label_size)
+}
+
+/// Add column numbers above and row numbers beside a chart for hand-knitting
+///
+/// Conventional charts are read starting from the bottom right, so their row
+/// numbers go on the right edge where each row starts. Reading order numbers
+/// go on the left.
+pub fn add_chart_numbers(chart: &GrayImage, gauge: Gauge, numbering: ChartNumbering) -> GrayImage {
+    let columns = chart.width() / gauge.width;
+    let rows = chart.height() / gauge.height;
+
+    let mut image = GrayImage::from_pixel(
+        chart.width() + ROW_NUMBERS_WIDTH,
+        chart.height() + COLUMN_NUMBERS_HEIGHT,
+        [255].into(),
+    );
+    let chart_x = match numbering {
+        ChartNumbering::Conventional => 0,
+        ChartNumbering::Reading => ROW_NUMBERS_WIDTH,
+    };
+    imageops::replace(
+        &mut image,
+        chart,
+        chart_x.into(),
+        COLUMN_NUMBERS_HEIGHT.into(),
+    );
+
+    let column_every = label_interval(COLUMN_LABEL_WIDTH, gauge.width);
+    for column in 0..columns {
+        let number = match numbering {
+            ChartNumbering::Conventional => columns - column,
+            ChartNumbering::Reading => column + 1,
+        };
+        if !is_labelled(number, column_every, gauge.width, COLUMN_LABEL_WIDTH) {
+            continue;
+        }
+
+        let text = number.to_string();
+        let text_width = text.len() as u32 * (GLYPH_WIDTH + 1) - 1;
+        let center_x = chart_x + column * gauge.width + gauge.width / 2;
+        let x = center_x
+            .saturating_sub(text_width / 2)
+            .min(image.width().saturating_sub(text_width));
+        draw_text(&mut image, &text, x.into(), 1);
+    }
+
+    let row_every = label_interval(ROW_LABEL_HEIGHT, gauge.height);
+    for row in 0..rows {
+        let number = match numbering {
+            ChartNumbering::Conventional => rows - row,
+            ChartNumbering::Reading => row + 1,
+        };
+        if !is_labelled(number, row_every, gauge.height, ROW_LABEL_HEIGHT) {
+            continue;
+        }
+
+        let text = number.to_string();
+        let x = match numbering {
+            ChartNumbering::Conventional => chart.width() + 2,
+            ChartNumbering::Reading => 0,
+        };
+        let center_y = COLUMN_NUMBERS_HEIGHT + row * gauge.height + gauge.height / 2;
+        draw_text(&mut image, &text, x.into(), i64::from(center_y) - 2);
+    }
+
+    image
+}
+
+/// Stitches shown at once on the simulated machine display
+const LCD_COLUMNS: u32 = 60;
+const LCD_ROWS: u32 = 20;
+
+/// Size of each display dot in pixels; the dots are taller than they are wide
+const LCD_DOT_WIDTH: u32 = 3;
+const LCD_DOT_HEIGHT: u32 = 4;
+const LCD_DOT_GAP: u32 = 1;
+const LCD_BORDER: u32 = 4;
+
+const LCD_BACKGROUND: u8 = 0xb4;
+const LCD_DOT_OFF: u8 = 0xa4;
+const LCD_DOT_ON: u8 = 0x28;
+
+/// Render a one-pixel-per-stitch chart the way a small dot matrix LCD shows it
+///
+/// The display has a fixed viewport of the bottom left part of the pattern,
+/// where knitting starts, with unlit dots wherever the pattern is smaller.
+pub fn lcd_screenshot(chart: &GrayImage) -> GrayImage {
+    let pitch_x = LCD_DOT_WIDTH + LCD_DOT_GAP;
+    let pitch_y = LCD_DOT_HEIGHT + LCD_DOT_GAP;
+    let mut image = GrayImage::from_pixel(
+        2 * LCD_BORDER + LCD_COLUMNS * pitch_x - LCD_DOT_GAP,
+        2 * LCD_BORDER + LCD_ROWS * pitch_y - LCD_DOT_GAP,
+        [LCD_BACKGROUND].into(),
+    );
+
+    let row_offset = chart.height().saturating_sub(LCD_ROWS);
+    for row in 0..LCD_ROWS {
+        for column in 0..LCD_COLUMNS {
+            let lit = column < chart.width()
+                && row + row_offset < chart.height()
+                && chart.get_pixel(column, row + row_offset)[0] < 128;
+            let color = if lit { LCD_DOT_ON } else { LCD_DOT_OFF };
+
+            for dy in 0..LCD_DOT_HEIGHT {
+                for dx in 0..LCD_DOT_WIDTH {
+                    image.put_pixel(
+                        LCD_BORDER + column * pitch_x + dx,
+                        LCD_BORDER + row * pitch_y + dy,
+                        [color].into(),
+                    );
+                }
+            }
+        }
+    }
+
+    image
+}
+
+/// Draw `text` in black with its top left corner at `x`, `y`, clipped to the image
+///
+/// Only digits, `L` and `R` have glyphs, other characters are left blank.
+pub fn draw_text(image: &mut GrayImage, text: &str, x: i64, y: i64) {
+    for (i, c) in text.chars().enumerate() {
+        let Some((_, glyph)) = GLYPHS.iter().find(|(g, _)| *g == c) else {
+            continue;
+        };
+
+        let glyph_x = x + i as i64 * i64::from(GLYPH_WIDTH + 1);
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+
+                let px = glyph_x + i64::from(col);
+                let py = y + row as i64;
+                if (0..i64::from(image.width())).contains(&px)
+                    && (0..i64::from(image.height())).contains(&py)
+                {
+                    image.put_pixel(px as u32, py as u32, [0].into());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_gauge() {
+    assert_eq!(
+        "4:3".parse::<Gauge>().unwrap(),
+        Gauge {
+            width: 4,
+            height: 3
+        }
+    );
+    assert!("4".parse::<Gauge>().is_err());
+    assert!("0:3".parse::<Gauge>().is_err());
+}
+
+#[test]
+fn test_apply_gauge() {
+    let image = GrayImage::from_raw(2, 1, vec![0, 255]).unwrap();
+    let scaled = apply_gauge(
+        &image,
+        Gauge {
+            width: 2,
+            height: 3,
+        },
+    );
+
+    assert_eq!(scaled.dimensions(), (4, 3));
+    for y in 0..3 {
+        assert_eq!(scaled.get_pixel(0, y)[0], 0);
+        assert_eq!(scaled.get_pixel(1, y)[0], 0);
+        assert_eq!(scaled.get_pixel(2, y)[0], 255);
+        assert_eq!(scaled.get_pixel(3, y)[0], 255);
+    }
+}
+
+#[test]
+fn test_needle_for_column() {
+    assert_eq!(Needle::for_column(0, 4, 0), Needle::Left(2));
+    assert_eq!(Needle::for_column(1, 4, 0), Needle::Left(1));
+    assert_eq!(Needle::for_column(2, 4, 0), Needle::Right(1));
+    assert_eq!(Needle::for_column(3, 4, 0), Needle::Right(2));
+    assert_eq!(Needle::for_column(0, 4, 10), Needle::Right(9));
+}
+
+#[test]
+fn test_add_needle_ruler() {
+    let chart = GrayImage::from_pixel(40, 3, [255].into());
+    let gauge = Gauge {
+        width: 2,
+        height: 1,
+    };
+    let image = add_needle_ruler(&chart, gauge, 0);
+
+    assert_eq!(image.dimensions(), (40, 3 + RULER_HEIGHT));
+    // Column 10 is R1 and gets a long tick
+    assert_eq!(image.get_pixel(21, RULER_HEIGHT - 4)[0], 0);
+    // Column 11 is R2 and gets a short tick
+    assert_eq!(image.get_pixel(23, RULER_HEIGHT - 4)[0], 255);
+    assert_eq!(image.get_pixel(23, RULER_HEIGHT - 1)[0], 0);
+}
+
+#[test]
+fn test_lcd_screenshot() {
+    // Taller than the display, with only the bottom left stitch selected
+    let mut chart = GrayImage::from_pixel(2, LCD_ROWS + 5, [255].into());
+    chart.put_pixel(0, LCD_ROWS + 4, [0].into());
+
+    let image = lcd_screenshot(&chart);
+    let dot = |column: u32, row: u32| {
+        image.get_pixel(
+            LCD_BORDER + column * (LCD_DOT_WIDTH + LCD_DOT_GAP),
+            LCD_BORDER + row * (LCD_DOT_HEIGHT + LCD_DOT_GAP),
+        )[0]
+    };
+
+    assert_eq!(dot(0, LCD_ROWS - 1), LCD_DOT_ON);
+    assert_eq!(dot(1, LCD_ROWS - 1), LCD_DOT_OFF);
+    assert_eq!(dot(0, 0), LCD_DOT_OFF);
+    assert_eq!(dot(LCD_COLUMNS - 1, 0), LCD_DOT_OFF);
+    assert_eq!(image.get_pixel(0, 0)[0], LCD_BACKGROUND);
+}
+
+#[test]
+fn test_add_chart_numbers() {
+    let chart = GrayImage::from_pixel(20, 10, [0].into());
+    let gauge = Gauge {
+        width: 4,
+        height: 2,
+    };
+
+    let conventional = add_chart_numbers(&chart, gauge, ChartNumbering::Conventional);
+    assert_eq!(
+        conventional.dimensions(),
+        (20 + ROW_NUMBERS_WIDTH, 10 + COLUMN_NUMBERS_HEIGHT)
+    );
+    // The chart stays on the left, with the numbers on its right
+    assert_eq!(conventional.get_pixel(0, COLUMN_NUMBERS_HEIGHT)[0], 0);
+    assert_eq!(
+        conventional.get_pixel(20 + ROW_NUMBERS_WIDTH - 1, 0)[0],
+        255
+    );
+
+    let reading = add_chart_numbers(&chart, gauge, ChartNumbering::Reading);
+    assert_eq!(
+        reading.get_pixel(ROW_NUMBERS_WIDTH, COLUMN_NUMBERS_HEIGHT)[0],
+        0
+    );
+    assert_eq!(
+        reading.get_pixel(ROW_NUMBERS_WIDTH - 1, COLUMN_NUMBERS_HEIGHT + 9)[0],
+        255
+    );
+}
+
+#[test]
+fn test_is_labelled() {
+    assert!(is_labelled(1, 2, 3, 5));
+    assert!(!is_labelled(2, 2, 3, 5));
+    assert!(!is_labelled(3, 2, 3, 5));
+    assert!(is_labelled(4, 2, 3, 5));
+}
+
+#[test]
+fn test_label_interval() {
+    assert_eq!(label_interval(12, 1), 20);
+    assert_eq!(label_interval(12, 4), 5);
+    assert_eq!(label_interval(5, 10), 1);
+}
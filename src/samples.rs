@@ -0,0 +1,136 @@
+//! Procedurally generated test patterns for demoing and exercising the tooling
+
+use image::GrayImage;
+
+use knitty2::{
+    kh940::{self, Pattern},
+    render, Result,
+};
+
+/// The kinds of generated patterns, used in turn
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SampleKind {
+    Checkerboard,
+    DiagonalStripes,
+    Gradient,
+    Label,
+}
+
+const KINDS: [SampleKind; 4] = [
+    SampleKind::Checkerboard,
+    SampleKind::DiagonalStripes,
+    SampleKind::Gradient,
+    SampleKind::Label,
+];
+
+/// Pattern sizes cycled through after every round of kinds, with odd sizes
+/// to catch row and nibble padding mistakes
+const SIZES: [(u16, u16); 8] = [
+    (8, 8),
+    (13, 7),
+    (24, 24),
+    (1, 31),
+    (37, 19),
+    (60, 45),
+    (101, 3),
+    (200, 63),
+];
+
+/// 4x4 Bayer matrix for ordered dithering of gradients
+const BAYER_4X4: [[u16; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Generate up to `count` sample patterns numbered from 901, as many as fit in memory
+///
+/// Patterns that would overflow the machine's pattern memory are left out,
+/// so fewer than `count` may be returned.
+pub fn sample_patterns(count: usize) -> Result<Vec<Pattern>> {
+    let mut free = kh940::MachineState::empty().free_pattern_memory();
+    let mut patterns = vec![];
+
+    for (index, number) in kh940::PATTERN_NUMBERS
+        .take(count.min(kh940::MAX_PATTERNS))
+        .enumerate()
+    {
+        let kind = KINDS[index % KINDS.len()];
+        let (width, height) = SIZES[index / KINDS.len() % SIZES.len()];
+        let pattern = sample_pattern(kind, number, width, height)?;
+
+        if pattern.memory_size() > free {
+            continue;
+        }
+        free -= pattern.memory_size();
+        patterns.push(pattern);
+    }
+
+    Ok(patterns)
+}
+
+fn sample_pattern(kind: SampleKind, number: u16, width: u16, height: u16) -> Result<Pattern> {
+    let (width, height) = (usize::from(width), usize::from(height));
+
+    let rows = match kind {
+        SampleKind::Checkerboard => bitmap(width, height, |x, y| (x / 2 + y / 2) % 2 == 0),
+        SampleKind::DiagonalStripes => bitmap(width, height, |x, y| (x + y) % 6 < 3),
+        // Dark on the left fading to nothing on the right
+        SampleKind::Gradient => bitmap(width, height, |x, y| {
+            let level = (16 * (width - x) / width) as u16;
+            BAYER_4X4[y % 4][x % 4] < level
+        }),
+        SampleKind::Label => label_bitmap(&number.to_string(), width, height),
+    };
+
+    Pattern::from_bitmap(number, rows)
+}
+
+fn bitmap(width: usize, height: usize, selected: impl Fn(usize, usize) -> bool) -> Vec<Vec<bool>> {
+    (0..height)
+        .map(|y| (0..width).map(|x| selected(x, y)).collect())
+        .collect()
+}
+
+/// Tile `text` across the canvas, clipping it where it doesn't fit
+fn label_bitmap(text: &str, width: usize, height: usize) -> Vec<Vec<bool>> {
+    let mut image = GrayImage::from_pixel(width as u32, height as u32, [255].into());
+
+    let step_x = text.len() as i64 * i64::from(render::GLYPH_WIDTH + 1) + 1;
+    let step_y = i64::from(render::GLYPH_HEIGHT + 2);
+    for y in (1..height as i64).step_by(step_y as usize) {
+        for x in (1..width as i64).step_by(step_x as usize) {
+            render::draw_text(&mut image, text, x, y);
+        }
+    }
+
+    bitmap(width, height, |x, y| {
+        image.get_pixel(x as u32, y as u32)[0] == 0
+    })
+}
+
+#[test]
+fn test_sample_patterns() {
+    let patterns = sample_patterns(kh940::MAX_PATTERNS).unwrap();
+    assert!(patterns.len() > SIZES.len() * KINDS.len());
+
+    let mut state = kh940::MachineState::empty();
+    for pattern in &patterns {
+        state.add_pattern(pattern.clone());
+    }
+    let data = state.serialize().unwrap();
+
+    let parsed = kh940::MachineState::from_memory_dump(&data).unwrap();
+    assert_eq!(parsed.patterns(), &patterns[..]);
+    assert!(parsed.inconsistent_patterns().is_empty());
+}
+
+#[test]
+fn test_sample_kinds() {
+    let label = sample_pattern(SampleKind::Label, 901, 13, 7).unwrap();
+    assert_eq!(
+        label.rows()[1][1..13],
+        [true, true, true, false, true, true, true, false, false, true, false, false]
+    );
+
+    let gradient = sample_pattern(SampleKind::Gradient, 901, 8, 4).unwrap();
+    let selected = |x: usize| gradient.rows().iter().filter(|row| row[x]).count();
+    assert_eq!(selected(0), 4);
+    assert!(selected(7) < selected(0));
+}
@@ -1,8 +1,9 @@
-#![allow(dead_code)] // FIXME remove this
+use crate::{
+    error::{KnittyError, Result},
+    Nibble,
+};
 
-use crate::Nibble;
-
-/// Convert a stream of 4 bit numbers to a stream of bits
+/// Convert a stream of 4 bit numbers to a stream of bits, see [`nibble_bits_iter`]
 pub fn nibble_bits(ns: &[Nibble]) -> Vec<bool> {
     let mut bits = vec![false; ns.len() * 4];
 
@@ -25,7 +26,21 @@ fn test_nibble_bits() {
     );
 }
 
-/// Convert a string of bytes to a stream of nibbles
+/// Lazily convert a stream of 4 bit numbers to a stream of bits, most significant first
+pub fn nibble_bits_iter(ns: impl IntoIterator<Item = Nibble>) -> impl Iterator<Item = bool> {
+    ns.into_iter().flat_map(|n| {
+        let n: u8 = n.into();
+        (0..4).rev().map(move |bit| n & (1 << bit) != 0)
+    })
+}
+
+#[test]
+fn test_nibble_bits_iter() {
+    let ns = [Nibble::new(1), Nibble::new(2), Nibble::new(15)];
+    assert_eq!(nibble_bits_iter(ns).collect::<Vec<_>>(), nibble_bits(&ns));
+}
+
+/// Convert a string of bytes to a stream of nibbles, see [`nibbles_iter`]
 pub fn to_nibbles(bs: &[u8]) -> Vec<Nibble> {
     let mut ns = vec![Nibble::ZERO; bs.len() * 2];
 
@@ -43,7 +58,26 @@ fn test_to_nibbles() {
     assert_eq!(to_nibbles(&[0x3d]), &[Nibble::new(3), Nibble::new(13)]);
 }
 
-/// Convert a stream of nibbles to a string of bytes
+/// Lazily convert a string of bytes to a stream of nibbles, high nibble first
+///
+/// Unlike [`to_nibbles`] this doesn't allocate, for callers that only walk
+/// the nibbles once.
+pub fn nibbles_iter(bs: &[u8]) -> impl Iterator<Item = Nibble> + '_ {
+    bs.iter().flat_map(|b| {
+        let (n1, n2) = Nibble::divide_byte(*b);
+        [n1, n2]
+    })
+}
+
+#[test]
+fn test_nibbles_iter() {
+    assert_eq!(
+        nibbles_iter(&[0x3d, 0x01]).collect::<Vec<_>>(),
+        to_nibbles(&[0x3d, 0x01])
+    );
+}
+
+/// Convert a stream of nibbles to a string of bytes, see [`from_nibbles_iter`]
 pub fn from_nibbles(ns: &[Nibble]) -> Vec<u8> {
     assert_eq!(ns.len() % 2, 0, "Must provide an even number of nibbles");
 
@@ -61,6 +95,20 @@ fn test_from_nibbles() {
     assert_eq!(from_nibbles(&[Nibble::new(3), Nibble::new(13)]), &[0x3d]);
 }
 
+/// Lazily combine pairs of nibbles into bytes
+///
+/// A trailing unpaired nibble is dropped, where [`from_nibbles`] would panic.
+pub fn from_nibbles_iter(ns: impl IntoIterator<Item = Nibble>) -> impl Iterator<Item = u8> {
+    let mut ns = ns.into_iter();
+    std::iter::from_fn(move || Some(Nibble::combine_nibbles(ns.next()?, ns.next()?)))
+}
+
+#[test]
+fn test_from_nibbles_iter() {
+    let ns = [Nibble::new(3), Nibble::new(13), Nibble::new(1)];
+    assert_eq!(from_nibbles_iter(ns).collect::<Vec<_>>(), &[0x3d]);
+}
+
 /// Convert a stream of nibbles representing a BCD (binary coded digit) to an integer
 pub fn from_bcd(ns: &[Nibble]) -> u16 {
     let mut s = 0;
@@ -120,6 +168,34 @@ fn test_to_bcd() {
     );
 }
 
+/// Convert an integer to exactly `width` BCD nibbles
+///
+/// Fails instead of emitting extra nibbles when the number has too many digits.
+pub fn to_bcd_fixed(n: u16, width: u16) -> Result<Vec<Nibble>> {
+    let ns = to_bcd(n, width);
+    if ns.len() != usize::from(width) {
+        return Err(KnittyError::BcdOverflow { value: n, width });
+    }
+
+    Ok(ns)
+}
+
+#[test]
+fn test_to_bcd_fixed() {
+    assert_eq!(
+        to_bcd_fixed(12, 3).unwrap(),
+        &[Nibble::ZERO, Nibble::new(1), Nibble::new(2)]
+    );
+    assert_eq!(to_bcd_fixed(999, 3).unwrap().len(), 3);
+    assert!(matches!(
+        to_bcd_fixed(1000, 3),
+        Err(KnittyError::BcdOverflow {
+            value: 1000,
+            width: 3
+        })
+    ));
+}
+
 /// Convert a sequence of bits to a string of bytes
 ///
 /// The bit sequence must have a length divisible by 8
@@ -171,3 +247,21 @@ fn test_padding() {
     assert_eq!(padding(3, 4), 1);
     assert_eq!(padding(4, 4), 0);
 }
+
+/// 64 bit FNV-1a hash, stable across platforms and toolchain versions
+pub fn fnv1a(bs: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    for b in bs.iter().copied() {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+#[test]
+fn test_fnv1a() {
+    assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+    assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+}
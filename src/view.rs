@@ -0,0 +1,82 @@
+//! A minimal window for paging through the patterns on a disk image
+
+use eyre::{Context, Result};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+use knitty2::kh940::Pattern;
+
+const WINDOW_WIDTH: usize = 800;
+const WINDOW_HEIGHT: usize = 600;
+
+/// Pixels per stitch when the window opens, and the range the scale can be changed in
+const DEFAULT_SCALE: usize = 4;
+const MAX_SCALE: usize = 16;
+
+const BACKGROUND: u32 = 0x808080;
+const SELECTED: u32 = 0x000000;
+const UNSELECTED: u32 = 0xffffff;
+
+/// Show the patterns in a window until it's closed
+///
+/// Left and right switch between patterns, `+` and `-` change the number of
+/// pixels per stitch, and escape or `q` closes the window. Patterns are drawn
+/// from the top left corner, and whatever doesn't fit the window is cut off.
+pub fn show_patterns(patterns: &[Pattern]) -> Result<()> {
+    let mut window = Window::new(
+        "knitty2",
+        WINDOW_WIDTH,
+        WINDOW_HEIGHT,
+        WindowOptions::default(),
+    )
+    .context("Could not open a window")?;
+    window.set_target_fps(30);
+
+    let mut buffer = vec![BACKGROUND; WINDOW_WIDTH * WINDOW_HEIGHT];
+    let mut index = 0;
+    let mut scale = DEFAULT_SCALE;
+
+    while window.is_open() {
+        for key in window.get_keys_pressed(KeyRepeat::Yes) {
+            match key {
+                Key::Right => index = (index + 1) % patterns.len(),
+                Key::Left => index = (index + patterns.len() - 1) % patterns.len(),
+                Key::Equal | Key::NumPadPlus => scale = (scale + 1).min(MAX_SCALE),
+                Key::Minus | Key::NumPadMinus => scale = (scale - 1).max(1),
+                Key::Escape | Key::Q => return Ok(()),
+                _ => {}
+            }
+        }
+
+        let pattern = &patterns[index];
+        window.set_title(&format!(
+            "Pattern {} ({}x{}), {} of {}, {scale}x",
+            pattern.pattern_number(),
+            pattern.width(),
+            pattern.height(),
+            index + 1,
+            patterns.len()
+        ));
+        draw_pattern(&mut buffer, pattern, scale);
+
+        window
+            .update_with_buffer(&buffer, WINDOW_WIDTH, WINDOW_HEIGHT)
+            .context("Could not draw the window")?;
+    }
+
+    Ok(())
+}
+
+fn draw_pattern(buffer: &mut [u32], pattern: &Pattern, scale: usize) {
+    let rows = pattern.rows();
+
+    for (y, line) in buffer.chunks_exact_mut(WINDOW_WIDTH).enumerate() {
+        let row = rows.get(y / scale);
+        for (x, pixel) in line.iter_mut().enumerate() {
+            *pixel = match row.and_then(|row| row.get(x / scale)) {
+                Some(true) => SELECTED,
+                Some(false) => UNSELECTED,
+                None => BACKGROUND,
+            };
+        }
+    }
+}